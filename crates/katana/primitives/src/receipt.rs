@@ -126,6 +126,16 @@ impl Receipt {
         }
     }
 
+    /// Returns the actual fee paid for the transaction.
+    pub fn actual_fee(&self) -> u128 {
+        match self {
+            Receipt::Invoke(rct) => rct.actual_fee,
+            Receipt::Declare(rct) => rct.actual_fee,
+            Receipt::L1Handler(rct) => rct.actual_fee,
+            Receipt::DeployAccount(rct) => rct.actual_fee,
+        }
+    }
+
     pub fn events(&self) -> &[Event] {
         match self {
             Receipt::Invoke(rct) => &rct.events,
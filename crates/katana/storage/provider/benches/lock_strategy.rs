@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use katana_primitives::block::{
+    Block, BlockHashOrNumber, BlockNumber, FinalityStatus, Header, SealedBlockWithStatus,
+};
+use katana_primitives::FieldElement;
+use katana_provider::providers::in_memory::sharded::{LockStrategy, DEFAULT_SHARD_COUNT};
+use katana_provider::providers::in_memory::InMemoryProvider;
+use katana_provider::traits::block::{BlockWriter, HeaderProvider};
+
+const BLOCKS: u64 = 1_000;
+const READERS: usize = 4;
+const READS_PER_READER: u64 = 10_000;
+
+fn insert_block(provider: &InMemoryProvider, number: BlockNumber) {
+    let header = Header { number, ..Default::default() };
+    let block = Block { header, body: vec![] }.seal_with_hash(FieldElement::from(number));
+    provider
+        .insert_block_with_states_and_receipts(
+            SealedBlockWithStatus { block, status: FinalityStatus::AcceptedOnL2 },
+            Default::default(),
+            vec![],
+        )
+        .unwrap();
+}
+
+fn provider_with_blocks(strategy: LockStrategy) -> InMemoryProvider {
+    let provider = InMemoryProvider::new().with_lock_strategy(strategy);
+    for number in 0..BLOCKS {
+        insert_block(&provider, number);
+    }
+    provider
+}
+
+/// Measures the time taken by concurrent readers to look up block headers while a writer keeps
+/// inserting blocks. Every iteration starts from a fresh provider holding [`BLOCKS`] blocks, so
+/// that the samples all measure maps of the same size.
+fn concurrent_header_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent header reads");
+
+    let strategies = [
+        ("single", LockStrategy::Single),
+        ("sharded", LockStrategy::Sharded { shards: DEFAULT_SHARD_COUNT }),
+    ];
+
+    for (name, strategy) in strategies {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &strategy, |b, strategy| {
+            b.iter_batched(
+                || provider_with_blocks(*strategy),
+                |provider| {
+                    let stop = AtomicBool::new(false);
+
+                    thread::scope(|s| {
+                        s.spawn(|| {
+                            let mut number = BLOCKS;
+                            while !stop.load(Ordering::Relaxed) {
+                                insert_block(&provider, number);
+                                number += 1;
+                            }
+                        });
+
+                        let readers = (0..READERS)
+                            .map(|_| {
+                                s.spawn(|| {
+                                    for i in 0..READS_PER_READER {
+                                        let id = BlockHashOrNumber::Num(i % BLOCKS);
+                                        black_box(provider.header(id).unwrap());
+                                    }
+                                })
+                            })
+                            .collect::<Vec<_>>();
+
+                        for reader in readers {
+                            reader.join().unwrap();
+                        }
+                        stop.store(true, Ordering::Relaxed);
+                    });
+
+                    provider
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(lock_strategy, concurrent_header_reads);
+criterion_main!(lock_strategy);
@@ -0,0 +1,58 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use katana_primitives::receipt::Receipt;
+use katana_primitives::FieldElement;
+
+const BLOOM_BITS: usize = 2048;
+const BLOOM_HASHES: u64 = 3;
+
+/// A bloom filter over the emitter addresses and the keys of the events of a block.
+///
+/// A bloom filter can return false positives but never false negatives: if
+/// [`BloomFilter::may_contain`] returns `false` the value is definitely not in the block, but if it
+/// returns `true` the block still has to be scanned to know whether it actually contains it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: [u64; BLOOM_BITS / 64],
+}
+
+impl BloomFilter {
+    /// Creates a bloom filter over the events of all the given receipts.
+    pub fn from_receipts(receipts: &[Receipt]) -> Self {
+        let mut bloom = Self::default();
+        for event in receipts.iter().flat_map(|receipt| receipt.events()) {
+            bloom.insert(&event.from_address);
+            event.keys.iter().for_each(|key| bloom.insert(key));
+        }
+        bloom
+    }
+
+    /// Adds a value to the filter.
+    pub fn insert(&mut self, value: &FieldElement) {
+        for bit in bit_positions(value) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns `false` if the value is definitely not in the filter, `true` if it may be.
+    pub fn may_contain(&self, value: &FieldElement) -> bool {
+        bit_positions(value).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self { bits: [0; BLOOM_BITS / 64] }
+    }
+}
+
+fn bit_positions(value: &FieldElement) -> impl Iterator<Item = usize> {
+    let bytes = value.to_bytes_be();
+    (0..BLOOM_HASHES).map(move |seed| {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        bytes.hash(&mut hasher);
+        hasher.finish() as usize % BLOOM_BITS
+    })
+}
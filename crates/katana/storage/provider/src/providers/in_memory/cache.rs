@@ -1,4 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::ops::Range;
 use std::sync::Arc;
 
 use katana_db::models::block::StoredBlockBodyIndices;
@@ -7,11 +9,15 @@ use katana_primitives::contract::{
     ClassHash, CompiledClassHash, CompiledContractClass, ContractAddress, GenericContractInfo,
     SierraClass, StorageKey, StorageValue,
 };
-use katana_primitives::receipt::Receipt;
+use katana_primitives::receipt::{Event, Receipt};
 use katana_primitives::state::{StateUpdates, StateUpdatesWithDeclaredClasses};
-use katana_primitives::transaction::{Tx, TxHash, TxNumber};
+use katana_primitives::transaction::{Tx, TxHash, TxNumber, TxWithHash};
+use katana_primitives::FieldElement;
 use parking_lot::RwLock;
 
+use super::bloom::BloomFilter;
+use super::event::EventIndex;
+
 type ContractStorageMap = HashMap<ContractAddress, HashMap<StorageKey, StorageValue>>;
 type ContractStateMap = HashMap<ContractAddress, GenericContractInfo>;
 
@@ -25,6 +31,7 @@ pub struct SharedContractClasses {
     pub(crate) compiled_classes: RwLock<CompiledClassesMap>,
 }
 
+#[derive(Clone)]
 pub struct CacheSnapshotWithoutClasses<Db> {
     pub(crate) db: Db,
     pub(crate) storage: ContractStorageMap,
@@ -41,6 +48,15 @@ pub struct CacheStateDb<Db> {
 }
 
 impl<Db> CacheStateDb<Db> {
+    /// Returns a rough estimate of the memory used by the state, excluding the classes, in bytes.
+    pub(crate) fn estimated_size(&self) -> usize {
+        estimate_state_size(
+            &self.storage.read(),
+            &self.contract_state.read(),
+            &self.compiled_class_hashes.read(),
+        )
+    }
+
     /// Applies the given state updates to the cache.
     pub fn insert_updates(&self, updates: StateUpdatesWithDeclaredClasses) {
         let mut storage = self.storage.write();
@@ -70,6 +86,33 @@ impl<Db> CacheStateDb<Db> {
     }
 }
 
+impl<Db> CacheSnapshotWithoutClasses<Db> {
+    /// Applies the given state updates to the snapshot.
+    pub(crate) fn apply_state_updates(&mut self, updates: &StateUpdates) {
+        for (contract_address, nonce) in &updates.nonce_updates {
+            let info = self.contract_state.entry(*contract_address).or_default();
+            info.nonce = *nonce;
+        }
+
+        for (contract_address, class_hash) in &updates.contract_updates {
+            let info = self.contract_state.entry(*contract_address).or_default();
+            info.class_hash = *class_hash;
+        }
+
+        for (contract_address, storage_changes) in &updates.storage_updates {
+            let contract_storage = self.storage.entry(*contract_address).or_default();
+            contract_storage.extend(storage_changes.iter().map(|(k, v)| (*k, *v)));
+        }
+
+        self.compiled_class_hashes.extend(updates.declared_classes.iter().map(|(k, v)| (*k, *v)));
+    }
+
+    /// Returns a rough estimate of the memory used by the snapshot, in bytes.
+    pub(crate) fn estimated_size(&self) -> usize {
+        estimate_state_size(&self.storage, &self.contract_state, &self.compiled_class_hashes)
+    }
+}
+
 pub struct CacheDb<Db> {
     pub(crate) db: Db,
     pub(crate) block_headers: HashMap<BlockNumber, Header>,
@@ -77,6 +120,11 @@ pub struct CacheDb<Db> {
     pub(crate) block_numbers: HashMap<BlockHash, BlockNumber>,
     pub(crate) block_statusses: HashMap<BlockNumber, FinalityStatus>,
     pub(crate) block_body_indices: HashMap<BlockNumber, StoredBlockBodyIndices>,
+    pub(crate) block_event_blooms: HashMap<BlockNumber, BloomFilter>,
+    /// The blocks inserted without their historical state.
+    pub(crate) blocks_without_history: HashSet<BlockNumber>,
+    /// The first block in which each storage slot was set to a non-zero value.
+    pub(crate) slots_first_nonzero: HashMap<(ContractAddress, StorageKey), BlockNumber>,
     pub(crate) latest_block_hash: BlockHash,
     pub(crate) latest_block_number: BlockNumber,
     pub(crate) state_update: HashMap<BlockNumber, StateUpdates>,
@@ -85,6 +133,8 @@ pub struct CacheDb<Db> {
     pub(crate) transaction_hashes: HashMap<TxNumber, TxHash>,
     pub(crate) transaction_numbers: HashMap<TxHash, TxNumber>,
     pub(crate) transaction_block: HashMap<TxNumber, BlockNumber>,
+    /// The index of the emitted events, only maintained if enabled.
+    pub(crate) event_index: Option<EventIndex>,
 }
 
 impl<Db> CacheStateDb<Db> {
@@ -113,13 +163,66 @@ impl<Db> CacheDb<Db> {
             transaction_block: HashMap::new(),
             transaction_hashes: HashMap::new(),
             block_body_indices: HashMap::new(),
+            block_event_blooms: HashMap::new(),
+            blocks_without_history: HashSet::new(),
+            slots_first_nonzero: HashMap::new(),
             transaction_numbers: HashMap::new(),
             latest_block_hash: Default::default(),
             latest_block_number: Default::default(),
+            event_index: None,
         }
     }
 }
 
+impl<Db> CacheDb<Db> {
+    /// Returns the transactions of a block, or `None` if the block doesn't exist.
+    pub(crate) fn block_body(&self, block_number: BlockNumber) -> Option<Vec<TxWithHash>> {
+        let indices = self.block_body_indices.get(&block_number)?;
+        let body = Range::<u64>::from(indices.clone())
+            .map(|tx_num| {
+                let hash = *self.transaction_hashes.get(&tx_num).expect("tx hash should exist");
+                let transaction = self.transactions[tx_num as usize].clone();
+                TxWithHash { hash, transaction }
+            })
+            .collect();
+        Some(body)
+    }
+
+    /// Returns a rough estimate of the memory used by the data of a block, ie. its header, its
+    /// transactions, its receipts and its state updates, in bytes. Returns `None` if the block
+    /// doesn't exist.
+    pub(crate) fn estimated_block_size(&self, block_number: BlockNumber) -> Option<usize> {
+        let indices = self.block_body_indices.get(&block_number)?;
+        let start = indices.tx_offset as usize;
+        let end = start + indices.tx_count as usize;
+
+        let header_size = size_of::<Header>() + size_of::<(BlockNumber, BlockHash)>() * 2;
+
+        let txs_size = indices.tx_count as usize
+            * (size_of::<Tx>() + size_of::<(TxNumber, TxHash)>() * 2)
+            + self.receipts[start..end]
+                .iter()
+                .map(|receipt| {
+                    let events = receipt.events();
+                    size_of::<Receipt>()
+                        + events.len() * size_of::<Event>()
+                        + events.iter().map(|e| e.keys.len() + e.data.len()).sum::<usize>()
+                            * size_of::<FieldElement>()
+                })
+                .sum::<usize>();
+
+        let state_update_size = self.state_update.get(&block_number).map_or(0, |updates| {
+            let storage = updates.storage_updates.values().map(HashMap::len).sum::<usize>();
+            storage * size_of::<(StorageKey, StorageValue)>()
+                + (updates.nonce_updates.len() + updates.contract_updates.len())
+                    * size_of::<(ContractAddress, FieldElement)>()
+                + updates.declared_classes.len() * size_of::<(ClassHash, CompiledClassHash)>()
+        });
+
+        Some(header_size + txs_size + state_update_size)
+    }
+}
+
 impl<Db> std::ops::Deref for CacheStateDb<Db> {
     type Target = Db;
     fn deref(&self) -> &Self::Target {
@@ -144,3 +247,22 @@ impl<Db: Clone> CacheStateDb<Db> {
         }
     }
 }
+
+/// Estimates the memory used by the entries of the state maps, ignoring the maps overhead.
+fn estimate_state_size(
+    storage: &ContractStorageMap,
+    contract_state: &ContractStateMap,
+    compiled_class_hashes: &CompiledClassHashesMap,
+) -> usize {
+    let storage_size = storage
+        .values()
+        .map(|slots| {
+            size_of::<(ContractAddress, HashMap<StorageKey, StorageValue>)>()
+                + slots.len() * size_of::<(StorageKey, StorageValue)>()
+        })
+        .sum::<usize>();
+
+    storage_size
+        + contract_state.len() * size_of::<(ContractAddress, GenericContractInfo)>()
+        + compiled_class_hashes.len() * size_of::<(ClassHash, CompiledClassHash)>()
+}
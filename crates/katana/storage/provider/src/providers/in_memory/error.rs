@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use katana_primitives::block::{BlockHash, BlockHashOrNumber, BlockNumber};
+use katana_primitives::contract::ClassHash;
+use katana_primitives::FieldElement;
+
+/// Errors returned by the in-memory provider specific APIs.
+#[derive(Debug, thiserror::Error)]
+pub enum InMemoryProviderError {
+    #[error("block {0} is missing from the requested range")]
+    MissingBlock(BlockNumber),
+
+    #[error("block {0:?} not found")]
+    BlockNotFound(BlockHashOrNumber),
+
+    #[error("class {0:#x} not found")]
+    ClassNotFound(ClassHash),
+
+    #[error("state has {entries} entries, more than the limit of {limit}")]
+    StateTooLarge { entries: usize, limit: usize },
+
+    #[error("invalid block range export: {0}")]
+    InvalidExport(String),
+
+    #[error("expected block {expected} to be imported but got block {actual}")]
+    NonContiguousBlock { expected: BlockNumber, actual: BlockNumber },
+
+    #[error("parent hash of block {number} is {actual:#x} but expected {expected:#x}")]
+    ParentHashMismatch { number: BlockNumber, expected: BlockHash, actual: BlockHash },
+
+    #[error("state root of block {number} is {actual:#x} but expected {expected:#x}")]
+    StateRootMismatch { number: BlockNumber, expected: FieldElement, actual: FieldElement },
+
+    #[error("block {number} has {transactions} transactions but {receipts} receipts")]
+    ReceiptCountMismatch { number: BlockNumber, transactions: usize, receipts: usize },
+
+    #[error("historical state of block {0} is not available")]
+    MissingHistoricalState(BlockNumber),
+
+    #[error("transactions of block {0} are not the last ones stored")]
+    TransactionsNotAtTip(BlockNumber),
+
+    #[error("storage lock not acquired within {0:?}")]
+    LockTimeout(Duration),
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use katana_primitives::block::BlockNumber;
+use katana_primitives::contract::ContractAddress;
+use katana_primitives::receipt::{Event, Receipt};
+use katana_primitives::transaction::TxHash;
+use katana_primitives::FieldElement;
+
+/// The position of an event in the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EventPosition {
+    /// The number of the block the event was emitted in.
+    pub block_number: BlockNumber,
+    /// The index of the receipt (ie. the transaction) in the block.
+    pub receipt_index: u64,
+    /// The index of the event in the receipt.
+    pub event_index: u64,
+}
+
+/// An event along with the transaction that emitted it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmittedEvent {
+    /// The hash of the transaction that emitted the event.
+    pub transaction_hash: TxHash,
+    /// The index of the transaction in the block.
+    pub transaction_index: u64,
+    /// The index of the event in the receipt of the transaction.
+    pub event_index: u64,
+    /// The event itself.
+    pub event: Event,
+}
+
+/// An index of the events keyed by their emitter address and by their first key, so that event
+/// queries can jump directly to the matching events instead of scanning every receipt.
+///
+/// Every indexed event costs one [`EventPosition`] (24 bytes) in each of the two maps, on top
+/// of one map entry per distinct address and per distinct key.
+#[derive(Debug, Default)]
+pub struct EventIndex {
+    by_address: HashMap<ContractAddress, Vec<EventPosition>>,
+    by_key: HashMap<FieldElement, Vec<EventPosition>>,
+}
+
+impl EventIndex {
+    /// Indexes the events of all the receipts of a block.
+    ///
+    /// Blocks must be indexed in ascending order for the positions to stay sorted.
+    pub fn insert_block(&mut self, block_number: BlockNumber, receipts: &[Receipt]) {
+        for (receipt_index, receipt) in receipts.iter().enumerate() {
+            for (event_index, event) in receipt.events().iter().enumerate() {
+                let position = EventPosition {
+                    block_number,
+                    receipt_index: receipt_index as u64,
+                    event_index: event_index as u64,
+                };
+
+                self.by_address.entry(event.from_address).or_default().push(position);
+                if let Some(key) = event.keys.first() {
+                    self.by_key.entry(*key).or_default().push(position);
+                }
+            }
+        }
+    }
+
+    /// Removes the events of the receipt at `receipt_index` in the given block from the index.
+    pub fn remove_receipt(&mut self, block_number: BlockNumber, receipt_index: u64) {
+        let is_removed =
+            |p: &EventPosition| p.block_number == block_number && p.receipt_index == receipt_index;
+
+        for positions in self.by_address.values_mut().chain(self.by_key.values_mut()) {
+            positions.retain(|p| !is_removed(p));
+        }
+
+        self.by_address.retain(|_, positions| !positions.is_empty());
+        self.by_key.retain(|_, positions| !positions.is_empty());
+    }
+
+    /// Removes the events of all the blocks above `block_number` from the index.
+    pub fn remove_blocks_above(&mut self, block_number: BlockNumber) {
+        for positions in self.by_address.values_mut().chain(self.by_key.values_mut()) {
+            positions.retain(|p| p.block_number <= block_number);
+        }
+
+        self.by_address.retain(|_, positions| !positions.is_empty());
+        self.by_key.retain(|_, positions| !positions.is_empty());
+    }
+
+    /// Returns the positions of the events emitted by `address` in the given range of blocks.
+    pub fn by_address(
+        &self,
+        address: ContractAddress,
+        range: RangeInclusive<BlockNumber>,
+    ) -> &[EventPosition] {
+        self.by_address.get(&address).map(|p| in_range(p, range)).unwrap_or_default()
+    }
+
+    /// Returns the positions of the events whose first key is `key` in the given range of blocks.
+    pub fn by_key(
+        &self,
+        key: FieldElement,
+        range: RangeInclusive<BlockNumber>,
+    ) -> &[EventPosition] {
+        self.by_key.get(&key).map(|p| in_range(p, range)).unwrap_or_default()
+    }
+}
+
+/// Returns `true` if the event matches the given emitter address and first key filters.
+pub(super) fn event_matches(
+    event: &Event,
+    from_address: Option<ContractAddress>,
+    key: Option<FieldElement>,
+) -> bool {
+    from_address.map_or(true, |address| event.from_address == address)
+        && key.map_or(true, |key| event.keys.first() == Some(&key))
+}
+
+fn in_range(positions: &[EventPosition], range: RangeInclusive<BlockNumber>) -> &[EventPosition] {
+    let start = positions.partition_point(|p| p.block_number < *range.start());
+    let end = positions.partition_point(|p| p.block_number <= *range.end());
+    &positions[start..end.max(start)]
+}
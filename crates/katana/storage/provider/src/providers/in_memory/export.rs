@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use katana_db::codecs::Decompress;
+use katana_db::models::block::StoredBlockBodyIndices;
+use katana_db::models::class::StoredContractClass;
+use katana_primitives::block::{
+    BlockHash, BlockNumber, FinalityStatus, Header, SealedBlock, SealedBlockWithStatus,
+    SealedHeader,
+};
+use katana_primitives::contract::{
+    ClassHash, CompiledClassHash, CompiledContractClass, SierraClass,
+};
+use katana_primitives::receipt::Receipt;
+use katana_primitives::state::{StateUpdates, StateUpdatesWithDeclaredClasses};
+use katana_primitives::transaction::TxWithHash;
+use serde::{Deserialize, Serialize};
+
+use super::error::InMemoryProviderError;
+
+/// A block exported from the in-memory provider, along with everything needed to insert it back
+/// into another provider.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct ExportedBlock {
+    pub(super) hash: BlockHash,
+    pub(super) header: Header,
+    pub(super) status: FinalityStatus,
+    pub(super) body: Vec<TxWithHash>,
+    pub(super) receipts: Vec<Receipt>,
+    pub(super) state_updates: StateUpdates,
+    /// The compiled classes declared in the block.
+    pub(super) compiled_classes: HashMap<ClassHash, StoredContractClass>,
+    /// The Sierra classes declared in the block, encoded as JSON because they can't be encoded
+    /// with postcard.
+    pub(super) sierra_classes: HashMap<ClassHash, Vec<u8>>,
+}
+
+impl ExportedBlock {
+    /// Decodes the exported block into the arguments expected by
+    /// [`BlockWriter::insert_block_with_states_and_receipts`](crate::traits::block::BlockWriter).
+    pub(super) fn into_parts(
+        self,
+    ) -> Result<
+        (SealedBlockWithStatus, StateUpdatesWithDeclaredClasses, Vec<Receipt>),
+        InMemoryProviderError,
+    > {
+        let declared_compiled_classes = self
+            .compiled_classes
+            .into_iter()
+            .map(|(hash, class)| (hash, CompiledContractClass::from(class)))
+            .collect();
+
+        let declared_sierra_classes = self
+            .sierra_classes
+            .into_iter()
+            .map(|(hash, bytes)| {
+                let class = SierraClass::decompress(bytes)
+                    .map_err(|e| InMemoryProviderError::InvalidExport(e.to_string()))?;
+                Ok((hash, class))
+            })
+            .collect::<Result<_, InMemoryProviderError>>()?;
+
+        let block = SealedBlockWithStatus {
+            block: SealedBlock {
+                header: SealedHeader { hash: self.hash, header: self.header },
+                body: self.body,
+            },
+            status: self.status,
+        };
+
+        let states = StateUpdatesWithDeclaredClasses {
+            state_updates: self.state_updates,
+            declared_sierra_classes,
+            declared_compiled_classes,
+        };
+
+        Ok((block, states, self.receipts))
+    }
+}
+
+/// A class exported from the in-memory provider with [`export_classes`].
+///
+/// [`export_classes`]: super::InMemoryProvider::export_classes
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct ExportedClass {
+    pub(super) class_hash: ClassHash,
+    pub(super) compiled_class_hash: Option<CompiledClassHash>,
+    pub(super) compiled_class: StoredContractClass,
+    /// The Sierra class, encoded as JSON because it can't be encoded with postcard.
+    pub(super) sierra_class: Option<Vec<u8>>,
+}
+
+impl ExportedClass {
+    /// Decodes the exported class into its class hash, compiled class hash, compiled class and
+    /// Sierra class.
+    pub(super) fn into_parts(
+        self,
+    ) -> Result<
+        (ClassHash, Option<CompiledClassHash>, CompiledContractClass, Option<SierraClass>),
+        InMemoryProviderError,
+    > {
+        let sierra_class = self
+            .sierra_class
+            .map(|bytes| {
+                SierraClass::decompress(bytes)
+                    .map_err(|e| InMemoryProviderError::InvalidExport(e.to_string()))
+            })
+            .transpose()?;
+
+        Ok((
+            self.class_hash,
+            self.compiled_class_hash,
+            CompiledContractClass::from(self.compiled_class),
+            sierra_class,
+        ))
+    }
+}
+
+/// Encodes the body indices of the given blocks, sorted by block number, as the deltas of the
+/// block number and of the transaction offset to the previous block, along with the transaction
+/// count. Both usually are small, so they take a single byte with postcard's varint encoding.
+pub(super) fn encode_body_indices(
+    indices: &[(BlockNumber, StoredBlockBodyIndices)],
+) -> Result<Vec<u8>, postcard::Error> {
+    let mut prev_number = 0u64;
+    let mut prev_end = 0u64;
+
+    let deltas = indices
+        .iter()
+        .map(|(number, indices)| {
+            // the deltas wrap around if a block was inserted out of order
+            let delta = (
+                number.wrapping_sub(prev_number),
+                indices.tx_offset.wrapping_sub(prev_end),
+                indices.tx_count,
+            );
+            prev_number = *number;
+            prev_end = indices.tx_offset.wrapping_add(indices.tx_count);
+            delta
+        })
+        .collect::<Vec<_>>();
+
+    postcard::to_stdvec(&deltas)
+}
+
+/// Decodes the body indices encoded with [`encode_body_indices`].
+pub(super) fn decode_body_indices(
+    bytes: &[u8],
+) -> Result<Vec<(BlockNumber, StoredBlockBodyIndices)>, InMemoryProviderError> {
+    let deltas: Vec<(u64, u64, u64)> = postcard::from_bytes(bytes)
+        .map_err(|e| InMemoryProviderError::InvalidExport(e.to_string()))?;
+
+    let mut number = 0u64;
+    let mut prev_end = 0u64;
+
+    let indices = deltas
+        .into_iter()
+        .map(|(number_delta, offset_delta, tx_count)| {
+            number = number.wrapping_add(number_delta);
+            let tx_offset = prev_end.wrapping_add(offset_delta);
+            prev_end = tx_offset.wrapping_add(tx_count);
+            (number, StoredBlockBodyIndices { tx_offset, tx_count })
+        })
+        .collect();
+
+    Ok(indices)
+}
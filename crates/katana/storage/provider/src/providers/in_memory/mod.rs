@@ -1,54 +1,2131 @@
+pub mod bloom;
 pub mod cache;
+pub mod error;
+pub mod event;
+mod export;
+pub mod sharded;
 pub mod state;
 
-use std::ops::RangeInclusive;
+#[cfg(test)]
+mod tests;
+
+use std::collections::{HashMap, HashSet};
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::stream::{self, Stream};
+use katana_db::codecs::Compress;
+use katana_db::models::block::StoredBlockBodyIndices;
+use katana_db::models::class::StoredContractClass;
+use katana_primitives::block::{
+    Block, BlockHash, BlockHashOrNumber, BlockNumber, BlockWithTxHashes, FinalityStatus, Header,
+    SealedBlock, SealedBlockWithStatus, SealedHeader,
+};
+use katana_primitives::contract::{
+    ClassHash, CompiledClassHash, CompiledContractClass, ContractAddress, GenericContractInfo,
+    Nonce, SierraClass, StorageKey, StorageValue,
+};
+use katana_primitives::receipt::Receipt;
+use katana_primitives::state::{StateUpdates, StateUpdatesWithDeclaredClasses};
+use katana_primitives::transaction::{DeclareTx, Tx, TxHash, TxNumber, TxWithHash};
+use katana_primitives::FieldElement;
+use parking_lot::RwLock;
+use starknet::core::crypto::pedersen_hash;
+use starknet::core::types::TransactionExecutionStatus;
+use starknet::macros::{felt, selector};
+use tokio::sync::Notify;
+
+use self::bloom::BloomFilter;
+use self::cache::CacheDb;
+use self::error::InMemoryProviderError;
+use self::event::{event_matches, EmittedEvent, EventIndex, EventPosition};
+use self::export::{decode_body_indices, encode_body_indices, ExportedBlock, ExportedClass};
+use self::sharded::{LockStrategy, ShardedBlockIndex};
+use self::state::{
+    HistoricalStates, InMemorySnapshot, InMemoryStateDb, LatestStateProvider, PendingStateProvider,
+    SnapshotStrategy, DIFF_SNAPSHOT_INTERVAL,
+};
+use crate::traits::block::{
+    BlockHashProvider, BlockNumberProvider, BlockProvider, BlockStatusProvider, BlockWriter,
+    HeaderProvider,
+};
+use crate::traits::contract::ContractClassWriter;
+use crate::traits::state::{StateFactoryProvider, StateProvider, StateRootProvider, StateWriter};
+use crate::traits::state_update::StateUpdateProvider;
+use crate::traits::transaction::{
+    ReceiptProvider, TransactionProvider, TransactionStatusProvider, TransactionsProviderExt,
+};
+
+/// Whether a transaction succeeded or was reverted.
+pub type ExecutionStatus = TransactionExecutionStatus;
+
+/// A callback invoked with the block number and the state of a historical snapshot right before
+/// it is evicted.
+pub type EvictSnapshotCallback = Box<dyn Fn(BlockNumber, &dyn StateProvider) + Send + Sync>;
+
+/// Computes the state root of a block from the state root of its parent and the state updates of
+/// the block.
+pub type StateRootFn = Box<dyn Fn(FieldElement, &StateUpdates) -> FieldElement + Send + Sync>;
+
+/// Everything needed to independently recompute the state root of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateRootInputs {
+    /// The number of the block.
+    pub block_number: BlockNumber,
+    /// The state root of the parent block, or zero for the genesis block.
+    pub parent_state_root: FieldElement,
+    /// The state root stored in the block header, to compare the recomputed root against.
+    pub state_root: FieldElement,
+    /// The state updates of the block.
+    pub state_updates: StateUpdates,
+}
+
+/// The average growth of the chain over a window of blocks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GrowthStats {
+    /// The number of blocks the averages are computed over.
+    pub blocks: u64,
+    /// The average number of transactions per block.
+    pub avg_txs_per_block: f64,
+    /// The average estimated memory used by the data of a block, in bytes.
+    pub avg_bytes_per_block: f64,
+}
+
+/// The maximum number of entries returned by [`InMemoryProvider::full_latest_state`].
+pub const FULL_STATE_MAX_ENTRIES: usize = 1_000_000;
+
+/// The address of the Universal Deployer Contract.
+pub const UDC_ADDRESS: ContractAddress =
+    ContractAddress(felt!("0x041a78e741e5af2fec34b695679bc6891742439f7afb8484ecd7766661ad02bf"));
+
+/// The complete latest state of the chain.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FullState {
+    /// The nonce and class hash of every contract.
+    pub contracts: HashMap<ContractAddress, GenericContractInfo>,
+    /// The storage of every contract.
+    pub storage: HashMap<ContractAddress, HashMap<StorageKey, StorageValue>>,
+    /// The mapping of the declared class hashes to their compiled class hashes.
+    pub compiled_class_hashes: HashMap<ClassHash, CompiledClassHash>,
+    /// The hashes of all the declared classes, including the Cairo 0 ones, in ascending order.
+    pub declared_classes: Vec<ClassHash>,
+}
+
+/// The latest state of a single contract, eg. to display an account.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AccountSnapshot {
+    /// The class hash of the contract.
+    pub class_hash: ClassHash,
+    /// The nonce of the contract.
+    pub nonce: Nonce,
+    /// The number of storage slots with a non-zero value.
+    pub storage_slot_count: usize,
+}
+
+/// A single piece of data handed to the sink of [`InMemoryProvider::drain_to`].
+#[derive(Debug, Clone)]
+pub enum PersistRecord {
+    /// The header of a block, emitted before the rest of the block data.
+    Header { hash: BlockHash, status: FinalityStatus, header: Header },
+    /// A transaction of the block of the last emitted header.
+    Transaction { tx_number: TxNumber, transaction: TxWithHash },
+    /// The receipt of the last emitted transaction.
+    Receipt { tx_number: TxNumber, receipt: Receipt },
+    /// The state updates of the block of the last emitted header, emitted after its transactions.
+    StateUpdate { block_number: BlockNumber, state_updates: StateUpdates },
+    /// A declared class, emitted after all the blocks.
+    Class {
+        class_hash: ClassHash,
+        compiled_class_hash: Option<CompiledClassHash>,
+        compiled_class: CompiledContractClass,
+        sierra_class: Option<SierraClass>,
+    },
+}
+
+pub struct InMemoryProvider {
+    storage: RwLock<CacheDb<()>>,
+    state: Arc<InMemoryStateDb>,
+    historical_states: RwLock<HistoricalStates<InMemorySnapshot>>,
+    /// Notifies the tasks waiting for new blocks whenever a block is inserted.
+    block_notify: Notify,
+    /// How the historical states are stored.
+    snapshot_strategy: SnapshotStrategy,
+    /// Invoked for every historical snapshot that is evicted.
+    on_evict_snapshot: RwLock<Option<EvictSnapshotCallback>>,
+    /// The state updates of the pending block, if any. Cleared whenever a block is inserted.
+    pending_state: RwLock<Option<Arc<StateUpdatesWithDeclaredClasses>>>,
+    /// The maximum estimated memory used by the states, in bytes.
+    memory_budget: Option<usize>,
+    /// Computes the state root the header of the inserted blocks are checked against, if set.
+    state_root_validator: Option<StateRootFn>,
+    /// The class hash first declared with each compiled class hash.
+    compiled_to_class: RwLock<HashMap<CompiledClassHash, ClassHash>>,
+    /// Whether the latest state has been written to directly, ie. through [`StateWriter`] or
+    /// [`ContractClassWriter`], since the last historical snapshot was created.
+    state_dirty: AtomicBool,
+    /// The sharded copy of the block lookups, only kept with [`LockStrategy::Sharded`].
+    block_index: Option<ShardedBlockIndex>,
+}
+
+impl InMemoryProvider {
+    pub fn new() -> Self {
+        let storage = RwLock::new(CacheDb::new(()));
+        let state = Arc::new(InMemoryStateDb::new(()));
+        let historical_states = RwLock::new(HistoricalStates::default());
+        let block_notify = Notify::new();
+        let snapshot_strategy = SnapshotStrategy::default();
+        let on_evict_snapshot = RwLock::new(None);
+        let pending_state = RwLock::new(None);
+        let memory_budget = None;
+        let state_root_validator = None;
+        let compiled_to_class = RwLock::new(HashMap::new());
+        let state_dirty = AtomicBool::new(false);
+        let block_index = None;
+        Self {
+            storage,
+            state,
+            historical_states,
+            block_notify,
+            snapshot_strategy,
+            on_evict_snapshot,
+            pending_state,
+            memory_budget,
+            state_root_validator,
+            compiled_to_class,
+            state_dirty,
+            block_index,
+        }
+    }
+
+    /// Sets the strategy used to store the historical states.
+    pub fn with_snapshot_strategy(mut self, strategy: SnapshotStrategy) -> Self {
+        self.snapshot_strategy = strategy;
+        self
+    }
+
+    /// Sets how the block lookups are synchronized with the writers, see [`LockStrategy`].
+    pub fn with_lock_strategy(mut self, strategy: LockStrategy) -> Self {
+        self.block_index = match strategy {
+            LockStrategy::Single => None,
+            LockStrategy::Sharded { shards } => {
+                let index = ShardedBlockIndex::new(shards);
+                index.fill(self.storage.get_mut());
+                Some(index)
+            }
+        };
+        self
+    }
+
+    /// Limits the estimated memory used by the latest state and the historical states to `bytes`.
+    ///
+    /// After each block insertion, the oldest historical states are evicted until the estimate is
+    /// under the budget. The latest state and the most recent historical state are always kept,
+    /// so the budget may be exceeded. The estimate only accounts for the contracts state and
+    /// storage, the classes and the blocks data are not included.
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Rejects the insertion of blocks whose header state root differs from the one computed by
+    /// `compute_state_root` from the state root of the parent block and the state updates of the
+    /// block.
+    ///
+    /// This lets a state root implementation, eg. a trie, be plugged in to validate the blocks
+    /// produced by others.
+    pub fn with_state_root_validation(mut self, compute_state_root: StateRootFn) -> Self {
+        self.state_root_validator = Some(compute_state_root);
+        self
+    }
+
+    /// Enables or disables the index of the emitted events used by [`Self::filter_events`].
+    ///
+    /// The index speeds up event queries at the cost of memory, see [`EventIndex`]. When enabled,
+    /// the events of the blocks already stored are indexed right away.
+    pub fn with_event_index(mut self, enabled: bool) -> Self {
+        let storage = self.storage.get_mut();
+
+        let index = enabled.then(|| {
+            let mut index = EventIndex::default();
+
+            let mut blocks = storage.block_body_indices.iter().collect::<Vec<_>>();
+            blocks.sort_by_key(|(num, _)| **num);
+
+            for (num, indices) in blocks {
+                let Range { start, end } = Range::<u64>::from(indices.clone());
+                index.insert_block(*num, &storage.receipts[start as usize..end as usize]);
+            }
+
+            index
+        });
+
+        storage.event_index = index;
+        self
+    }
+}
+
+impl Default for InMemoryProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryProvider {
+    /// Returns the sum of the `actual_fee` of all the receipts in the given range of blocks.
+    ///
+    /// The range must be contiguous, an error is returned if any of the blocks in the range is
+    /// missing.
+    pub fn total_fees_in_range(&self, range: RangeInclusive<u64>) -> Result<FieldElement> {
+        let storage = self.storage.read();
+
+        let mut total = FieldElement::ZERO;
+        for num in range {
+            let Some(indices) = storage.block_body_indices.get(&num) else {
+                return Err(InMemoryProviderError::MissingBlock(num).into());
+            };
+
+            let Range { start, end } = Range::<u64>::from(indices.clone());
+            total = storage.receipts[start as usize..end as usize]
+                .iter()
+                .fold(total, |acc, receipt| acc + FieldElement::from(receipt.actual_fee()));
+        }
+
+        Ok(total)
+    }
+
+    /// Returns the entire storage of a contract as of the given block, based on the historical
+    /// state of the block.
+    ///
+    /// Returns `None` if the historical state of the block is not available.
+    pub fn contract_storage_at(
+        &self,
+        address: ContractAddress,
+        block: BlockNumber,
+    ) -> Result<Option<HashMap<StorageKey, StorageValue>>> {
+        let storage = self.storage.read();
+        let state = self.historical_state(&storage, &self.historical_states.read(), block);
+        Ok(state.map(|state| state.inner.storage.get(&address).cloned().unwrap_or_default()))
+    }
+
+    /// Waits until the chain tip reaches at least `min_number` and returns the tip block number.
+    ///
+    /// Returns immediately if the tip is already at or above `min_number`, otherwise waits for new
+    /// blocks to be inserted.
+    pub async fn wait_for_block(&self, min_number: BlockNumber) -> Result<BlockNumber> {
+        loop {
+            // register for the notification before checking the tip so that a block inserted in
+            // between is not missed.
+            let notified = self.block_notify.notified();
+
+            let tip = {
+                let storage = self.storage.read();
+                let has_blocks = !storage.block_hashes.is_empty();
+                Some(storage.latest_block_number).filter(|tip| has_blocks && *tip >= min_number)
+            };
+
+            if let Some(tip) = tip {
+                return Ok(tip);
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Returns a stream that first yields all the blocks from `start` up to the current tip, then
+    /// keeps yielding the newly inserted blocks as they arrive.
+    ///
+    /// Blocks are yielded one at a time by number, so blocks inserted while catching up are
+    /// yielded exactly once and in order.
+    pub fn follow_from(&self, start: BlockNumber) -> impl Stream<Item = Result<Block>> + '_ {
+        stream::unfold(start, move |next| async move {
+            let block = match self.wait_for_block(next).await {
+                Ok(_) => self.block(BlockHashOrNumber::Num(next)),
+                Err(e) => Err(e),
+            };
+            let block = block.and_then(|block| {
+                block.ok_or_else(|| InMemoryProviderError::MissingBlock(next).into())
+            });
+            Some((block, next + 1))
+        })
+    }
+
+    /// Removes the most recent transaction along with its receipt and index mappings, and
+    /// decrements the transaction count of the block it belongs to.
+    ///
+    /// This is meant for building (possibly corrupted) test fixtures and must not be used on a
+    /// live chain.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn remove_last_transaction(&self) -> Result<Option<TxWithHash>> {
+        let mut storage = self.storage.write();
+
+        let Some(transaction) = storage.transactions.pop() else { return Ok(None) };
+        let number = storage.transactions.len() as TxNumber;
+
+        storage.receipts.truncate(number as usize);
+
+        let hash = storage.transaction_hashes.remove(&number).expect("tx hash should exist");
+        storage.transaction_numbers.remove(&hash);
+
+        let block_num = storage.transaction_block.remove(&number).expect("block num should exist");
+        let indices = storage.block_body_indices.get_mut(&block_num).expect("should exist");
+        indices.tx_count -= 1;
+
+        let receipt_index = number - indices.tx_offset;
+        if let Some(index) = storage.event_index.as_mut() {
+            index.remove_receipt(block_num, receipt_index);
+        }
+
+        Ok(Some(TxWithHash { hash, transaction }))
+    }
+
+    /// Returns all the receipts in the given range of blocks that contain at least one event whose
+    /// first key matches `key`, along with the hash of their transactions.
+    ///
+    /// This performs a linear scan over every event of every receipt in the range, so its cost
+    /// grows with the number of events in the range. Missing blocks are skipped.
+    pub fn receipts_with_event_key(
+        &self,
+        key: FieldElement,
+        range: RangeInclusive<u64>,
+    ) -> Result<Vec<(TxHash, Receipt)>> {
+        let storage = self.storage.read();
+
+        let mut receipts = Vec::new();
+        for num in range {
+            let Some(indices) = storage.block_body_indices.get(&num) else { continue };
+
+            for tx_num in Range::<u64>::from(indices.clone()) {
+                let receipt = &storage.receipts[tx_num as usize];
+                if receipt.events().iter().any(|event| event.keys.first() == Some(&key)) {
+                    let hash =
+                        *storage.transaction_hashes.get(&tx_num).expect("tx hash should exist");
+                    receipts.push((hash, receipt.clone()));
+                }
+            }
+        }
+
+        Ok(receipts)
+    }
+
+    /// Returns the positions of the events in the given range of blocks that are emitted by
+    /// `from_address` and whose first key is `key`, in emission order. A `None` filter matches
+    /// every event.
+    ///
+    /// If the event index is enabled, only the events matching one of the filters are visited.
+    /// Otherwise, the receipts of the blocks in the range whose [`BloomFilter`] may match the
+    /// filters are scanned.
+    pub fn filter_events(
+        &self,
+        from_address: Option<ContractAddress>,
+        key: Option<FieldElement>,
+        range: RangeInclusive<BlockNumber>,
+    ) -> Result<Vec<EventPosition>> {
+        let storage = self.storage.read();
+
+        let candidates = storage.event_index.as_ref().and_then(|index| match (from_address, key) {
+            (Some(address), _) => Some(index.by_address(address, range.clone())),
+            (None, Some(key)) => Some(index.by_key(key, range.clone())),
+            (None, None) => None,
+        });
+
+        // resolve the event at the given position
+        let event_at = |position: &EventPosition| {
+            let indices = storage.block_body_indices.get(&position.block_number)?;
+            let receipt =
+                storage.receipts.get((indices.tx_offset + position.receipt_index) as usize)?;
+            receipt.events().get(position.event_index as usize)
+        };
+
+        if let Some(candidates) = candidates {
+            let events = candidates
+                .iter()
+                .filter(|p| event_at(*p).map_or(false, |e| event_matches(e, from_address, key)))
+                .copied()
+                .collect();
+            return Ok(events);
+        }
+
+        // a block can only contain matching events if its bloom may contain both filters
+        let bloom_matches = |bloom: &BloomFilter| {
+            from_address.map_or(true, |address| bloom.may_contain(&address))
+                && key.map_or(true, |key| bloom.may_contain(&key))
+        };
+
+        let mut events = Vec::new();
+        for block_number in range {
+            let Some(indices) = storage.block_body_indices.get(&block_number) else { continue };
+            if !storage.block_event_blooms.get(&block_number).map_or(true, bloom_matches) {
+                continue;
+            }
+            let Range { start, end } = Range::<u64>::from(indices.clone());
+
+            let receipts = &storage.receipts[start as usize..end as usize];
+            for (receipt_index, receipt) in receipts.iter().enumerate() {
+                for (event_index, event) in receipt.events().iter().enumerate() {
+                    if event_matches(event, from_address, key) {
+                        events.push(EventPosition {
+                            block_number,
+                            receipt_index: receipt_index as u64,
+                            event_index: event_index as u64,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Returns the headers of the given blocks, in the same order as `ids`.
+    ///
+    /// All the ids are resolved under a single read lock. Unknown blocks are returned as `None`.
+    pub fn headers(&self, ids: &[BlockHashOrNumber]) -> Result<Vec<Option<Header>>> {
+        let storage = self.storage.read();
+
+        let headers = ids
+            .iter()
+            .map(|id| {
+                let num = match id {
+                    BlockHashOrNumber::Num(num) => Some(*num),
+                    BlockHashOrNumber::Hash(hash) => storage.block_numbers.get(hash).copied(),
+                };
+                num.and_then(|num| storage.block_headers.get(&num).cloned())
+            })
+            .collect();
+
+        Ok(headers)
+    }
+
+    /// Returns the total number of transactions in all the blocks up to and including the given
+    /// block, or `None` if the block doesn't exist.
+    pub fn cumulative_tx_count(&self, block_number: BlockNumber) -> Result<Option<u64>> {
+        let storage = self.storage.read();
+        let count = storage.block_body_indices.get(&block_number).map(|i| i.tx_offset + i.tx_count);
+        Ok(count)
+    }
+
+    /// Returns the total number of events emitted in all the blocks up to and including the given
+    /// block, or `None` if the block doesn't exist.
+    ///
+    /// The events are counted on demand, so this is linear in the number of transactions up to
+    /// the block.
+    pub fn cumulative_event_count(&self, block_number: BlockNumber) -> Result<Option<u64>> {
+        let storage = self.storage.read();
+        let count = storage.block_body_indices.get(&block_number).map(|i| {
+            let end = (i.tx_offset + i.tx_count) as usize;
+            storage.receipts[..end].iter().map(|r| r.events().len() as u64).sum()
+        });
+        Ok(count)
+    }
+
+    /// Sets the callback invoked right before a historical snapshot is evicted, eg. to persist it
+    /// to a slower storage tier. Replaces any previously set callback.
+    pub fn set_on_evict_snapshot(&self, cb: EvictSnapshotCallback) {
+        *self.on_evict_snapshot.write() = Some(cb);
+    }
+
+    /// Hands the evicted snapshots to the eviction callback, if any, before dropping them.
+    fn on_snapshots_evicted(&self, evicted: Vec<(BlockNumber, Arc<InMemorySnapshot>)>) {
+        if let Some(cb) = self.on_evict_snapshot.read().as_ref() {
+            for (num, state) in &evicted {
+                cb(*num, state.as_ref());
+            }
+        }
+    }
+
+    /// Returns the most recent transaction, or `None` if there are no transactions.
+    pub fn latest_transaction(&self) -> Result<Option<TxWithHash>> {
+        let storage = self.storage.read();
+
+        let Some(transaction) = storage.transactions.last().cloned() else { return Ok(None) };
+        let number = storage.transactions.len() as TxNumber - 1;
+        let hash = *storage.transaction_hashes.get(&number).expect("tx hash should exist");
+
+        Ok(Some(TxWithHash { hash, transaction }))
+    }
+
+    /// Exports the blocks in the given range, along with their state updates and the classes
+    /// declared in them, so that they can be transferred to another provider with
+    /// [`Self::import_block_range`].
+    ///
+    /// The range must be contiguous, an error is returned if any of the blocks in the range is
+    /// missing.
+    pub fn export_block_range(&self, range: RangeInclusive<u64>) -> Result<Vec<u8>> {
+        let storage = self.storage.read();
+        let sierra_classes = self.state.shared_contract_classes.sierra_classes.read();
+        let compiled_classes = self.state.shared_contract_classes.compiled_classes.read();
+
+        let mut blocks = Vec::new();
+        for num in range {
+            let Some(indices) = storage.block_body_indices.get(&num) else {
+                return Err(InMemoryProviderError::MissingBlock(num).into());
+            };
+
+            let Range { start, end } = Range::<u64>::from(indices.clone());
+            let body = (start..end)
+                .map(|tx_num| {
+                    let hash =
+                        *storage.transaction_hashes.get(&tx_num).expect("tx hash should exist");
+                    let transaction = storage.transactions[tx_num as usize].clone();
+                    TxWithHash { hash, transaction }
+                })
+                .collect::<Vec<_>>();
+
+            let state_updates = storage.state_update.get(&num).cloned().unwrap_or_default();
+
+            // legacy classes are not part of the declared classes of the state updates, so they
+            // are looked up from the declare transactions instead.
+            let declared = state_updates.declared_classes.keys().copied().chain(
+                body.iter().filter_map(|tx| match &tx.transaction {
+                    Tx::Declare(tx) => Some(tx.class_hash()),
+                    _ => None,
+                }),
+            );
+
+            let mut exported_compiled_classes = HashMap::new();
+            let mut exported_sierra_classes = HashMap::new();
+            for hash in declared {
+                if let Some(class) = compiled_classes.get(&hash) {
+                    exported_compiled_classes
+                        .insert(hash, StoredContractClass::from(class.clone()));
+                }
+                if let Some(class) = sierra_classes.get(&hash) {
+                    exported_sierra_classes.insert(hash, class.clone().compress());
+                }
+            }
+
+            blocks.push(ExportedBlock {
+                hash: *storage.block_hashes.get(&num).expect("block hash should exist"),
+                header: storage.block_headers.get(&num).cloned().expect("header should exist"),
+                status: *storage.block_statusses.get(&num).expect("block status should exist"),
+                body,
+                receipts: storage.receipts[start as usize..end as usize].to_vec(),
+                state_updates,
+                compiled_classes: exported_compiled_classes,
+                sierra_classes: exported_sierra_classes,
+            });
+        }
+
+        Ok(postcard::to_stdvec(&blocks)?)
+    }
+
+    /// Imports the blocks exported with [`Self::export_block_range`] on top of the current tip.
+    ///
+    /// The exported blocks must directly follow the tip: the first block number must be the tip
+    /// number plus one and its parent hash must be the tip hash, or it must be the genesis block
+    /// if the provider is empty. All the blocks are validated before any of them is inserted,
+    /// including their receipt counts and, if the validation is enabled, their state roots, so
+    /// that nothing is imported if any block is invalid. The storage lock is held from the
+    /// validation until the last block is inserted.
+    pub fn import_block_range(&self, bytes: &[u8]) -> Result<()> {
+        let blocks: Vec<ExportedBlock> = postcard::from_bytes(bytes)
+            .map_err(|e| InMemoryProviderError::InvalidExport(e.to_string()))?;
+
+        let mut storage = self.storage.write();
+
+        let (mut expected_number, mut expected_parent) = if storage.block_hashes.is_empty() {
+            (0, None)
+        } else {
+            (storage.latest_block_number + 1, Some(storage.latest_block_hash))
+        };
+
+        for block in &blocks {
+            let number = block.header.number;
+            if number != expected_number {
+                let err = InMemoryProviderError::NonContiguousBlock {
+                    expected: expected_number,
+                    actual: number,
+                };
+                return Err(err.into());
+            }
+
+            if let Some(expected) = expected_parent {
+                let actual = block.header.parent_hash;
+                if actual != expected {
+                    let err =
+                        InMemoryProviderError::ParentHashMismatch { number, expected, actual };
+                    return Err(err.into());
+                }
+            }
+
+            expected_number += 1;
+            expected_parent = Some(block.hash);
+        }
+
+        let blocks =
+            blocks.into_iter().map(ExportedBlock::into_parts).collect::<Result<Vec<_>, _>>()?;
+
+        // the blocks are validated on top of each other before the first one is inserted, so that
+        // an invalid block leaves the provider unchanged.
+        let mut parent_root = None;
+        for (block, states, receipts) in &blocks {
+            let number = block.block.header.header.number;
+            self.validate_block(block, states, receipts, || match parent_root {
+                Some(root) => Ok(root),
+                None => parent_state_root(&storage, number),
+            })?;
+            parent_root = Some(block.block.header.header.state_root);
+        }
+
+        let mut evicted = Vec::new();
+        let mut result = Ok(());
+        for (block, states, receipts) in blocks {
+            match self.insert_block_with_storage(&mut storage, block, states, receipts, true) {
+                Ok(states) => evicted.extend(states),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        drop(storage);
+
+        self.on_snapshots_evicted(evicted);
+        self.block_notify.notify_waiters();
+
+        result
+    }
+
+    /// Replays the blocks of a file written with the output of [`Self::export_block_range`],
+    /// stopping after block `up_to` if given, and returns the number of blocks applied.
+    ///
+    /// The blocks of the file that are not above the tip are skipped, so that a recorded session
+    /// can be stepped through with successive calls. The remaining blocks are imported as with
+    /// [`Self::import_block_range`] and must directly follow the tip.
+    pub fn replay_into(&self, wal_path: &Path, up_to: Option<BlockNumber>) -> Result<u64> {
+        let bytes = std::fs::read(wal_path)?;
+        let blocks: Vec<ExportedBlock> = postcard::from_bytes(&bytes)
+            .map_err(|e| InMemoryProviderError::InvalidExport(e.to_string()))?;
+
+        let next_number = {
+            let storage = self.storage.read();
+            if storage.block_hashes.is_empty() {
+                0
+            } else {
+                storage.latest_block_number + 1
+            }
+        };
+
+        let blocks = blocks
+            .into_iter()
+            .filter(|block| block.header.number >= next_number)
+            .filter(|block| up_to.map_or(true, |up_to| block.header.number <= up_to))
+            .collect::<Vec<_>>();
+
+        let applied = blocks.len() as u64;
+        self.import_block_range(&postcard::to_stdvec(&blocks)?)?;
+
+        Ok(applied)
+    }
+
+    /// Exports the given declared classes, or all of them if `hashes` is `None`, along with their
+    /// Sierra class and compiled class hash if any, eg. to bootstrap the classes of another
+    /// provider without the blocks. The classes can be imported with [`Self::import_classes`].
+    ///
+    /// An error is returned if any of the given classes is not declared.
+    pub fn export_classes(&self, hashes: Option<&[ClassHash]>) -> Result<Vec<u8>> {
+        let compiled_class_hashes = self.state.compiled_class_hashes.read();
+        let sierra_classes = self.state.shared_contract_classes.sierra_classes.read();
+        let compiled_classes = self.state.shared_contract_classes.compiled_classes.read();
+
+        let hashes = match hashes {
+            Some(hashes) => hashes.to_vec(),
+            None => {
+                let mut hashes = compiled_classes.keys().copied().collect::<Vec<_>>();
+                hashes.sort_unstable();
+                hashes
+            }
+        };
+
+        let classes = hashes
+            .into_iter()
+            .map(|hash| {
+                let Some(class) = compiled_classes.get(&hash) else {
+                    return Err(InMemoryProviderError::ClassNotFound(hash));
+                };
+                Ok(ExportedClass {
+                    class_hash: hash,
+                    compiled_class_hash: compiled_class_hashes.get(&hash).copied(),
+                    compiled_class: StoredContractClass::from(class.clone()),
+                    sierra_class: sierra_classes.get(&hash).map(|class| class.clone().compress()),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(postcard::to_stdvec(&classes)?)
+    }
+
+    /// Imports the classes exported with [`Self::export_classes`]. Existing classes with the same
+    /// hash are replaced. All the classes are decoded before any of them is imported.
+    pub fn import_classes(&self, bytes: &[u8]) -> Result<()> {
+        let classes: Vec<ExportedClass> = postcard::from_bytes(bytes)
+            .map_err(|e| InMemoryProviderError::InvalidExport(e.to_string()))?;
+
+        let classes =
+            classes.into_iter().map(ExportedClass::into_parts).collect::<Result<Vec<_>, _>>()?;
+
+        for (hash, compiled_class_hash, compiled_class, sierra_class) in classes {
+            self.set_class(hash, compiled_class)?;
+            if let Some(sierra_class) = sierra_class {
+                self.set_sierra_class(hash, sierra_class)?;
+            }
+            if let Some(compiled_hash) = compiled_class_hash {
+                self.set_compiled_class_hash_of_class_hash(hash, compiled_hash)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Exports the body indices of all the blocks, ie. the transaction offset and count of each
+    /// block, in a compact delta encoding that can be decoded with
+    /// [`Self::import_body_indices`].
+    pub fn export_body_indices(&self) -> Result<Vec<u8>> {
+        let storage = self.storage.read();
+
+        let mut indices = storage
+            .block_body_indices
+            .iter()
+            .map(|(num, indices)| (*num, indices.clone()))
+            .collect::<Vec<_>>();
+        indices.sort_unstable_by_key(|(num, _)| *num);
+
+        Ok(encode_body_indices(&indices)?)
+    }
+
+    /// Decodes the body indices exported with [`Self::export_body_indices`], sorted by block
+    /// number.
+    pub fn import_body_indices(bytes: &[u8]) -> Result<Vec<(BlockNumber, StoredBlockBodyIndices)>> {
+        Ok(decode_body_indices(bytes)?)
+    }
+
+    /// Returns `true` if a block already exists at `block_number` with a hash different from
+    /// `new_hash`, ie. inserting the new block would reorganize the chain.
+    pub fn would_reorg(&self, block_number: BlockNumber, new_hash: BlockHash) -> Result<bool> {
+        let storage = self.storage.read();
+        Ok(storage.block_hashes.get(&block_number).map_or(false, |hash| *hash != new_hash))
+    }
+
+    /// Returns both the compiled class and the Sierra class of the given class hash, resolved
+    /// together. The Sierra class of a Cairo 0 class is always `None`.
+    pub fn class_pair(
+        &self,
+        hash: ClassHash,
+    ) -> Result<(Option<CompiledContractClass>, Option<SierraClass>)> {
+        let sierra_classes = self.state.shared_contract_classes.sierra_classes.read();
+        let compiled_classes = self.state.shared_contract_classes.compiled_classes.read();
+        Ok((compiled_classes.get(&hash).cloned(), sierra_classes.get(&hash).cloned()))
+    }
+
+    /// Returns the number of storage slots with a non-zero value of a contract in the latest
+    /// state.
+    pub fn storage_slot_count(&self, address: ContractAddress) -> Result<usize> {
+        let storage = self.state.storage.read();
+        Ok(storage.get(&address).map_or(0, count_set_slots))
+    }
+
+    /// Returns the `n` contracts with the most storage slots with a non-zero value in the latest
+    /// state, along with their slot count, in descending order of slot count. Contracts with the
+    /// same slot count are ordered by address.
+    pub fn top_contracts_by_storage(&self, n: usize) -> Result<Vec<(ContractAddress, usize)>> {
+        let storage = self.state.storage.read();
+
+        let mut contracts = storage
+            .iter()
+            .map(|(address, slots)| (*address, count_set_slots(slots)))
+            .filter(|(_, count)| *count > 0)
+            .collect::<Vec<_>>();
+
+        contracts.sort_by(|(a_addr, a_count), (b_addr, b_count)| {
+            b_count.cmp(a_count).then_with(|| a_addr.cmp(b_addr))
+        });
+        contracts.truncate(n);
+
+        Ok(contracts)
+    }
+
+    /// Sets the state updates of the pending block, replacing the previous ones. They are
+    /// cleared once the next block is inserted.
+    pub fn set_pending_state(&self, updates: StateUpdatesWithDeclaredClasses) {
+        *self.pending_state.write() = Some(Arc::new(updates));
+    }
+
+    /// Returns the latest state with the pending state updates applied on top of it, or just the
+    /// latest state if there is no pending block.
+    pub fn pending_state(&self) -> Result<Box<dyn StateProvider>> {
+        let latest = LatestStateProvider(Arc::clone(&self.state));
+        match self.pending_state.read().as_ref() {
+            Some(pending) => {
+                Ok(Box::new(PendingStateProvider { latest, pending: Arc::clone(pending) }))
+            }
+            None => Ok(Box::new(latest)),
+        }
+    }
+
+    /// Checks that the `parent_hash` of every block in the given range, except the first one,
+    /// equals the hash of the block before it. Returns the number of the first block that
+    /// doesn't link to its parent, or `None` if the whole range forms a chain.
+    ///
+    /// The range must be contiguous, an error is returned if any of the blocks in the range is
+    /// missing.
+    pub fn verify_chain_links(&self, range: RangeInclusive<u64>) -> Result<Option<BlockNumber>> {
+        first_broken_link(&self.storage.read(), range)
+    }
+
+    /// Returns the transaction with the given number, without its hash.
+    pub fn raw_transaction_by_number(&self, num: TxNumber) -> Result<Option<Tx>> {
+        Ok(self.storage.read().transactions.get(num as usize).cloned())
+    }
+
+    /// Returns the bloom filter over the emitter addresses and keys of the events of the given
+    /// block, or `None` if the block doesn't exist.
+    pub fn block_event_bloom(&self, block_number: BlockNumber) -> Result<Option<BloomFilter>> {
+        Ok(self.storage.read().block_event_blooms.get(&block_number).cloned())
+    }
+
+    /// Returns the inputs needed to recompute the state root of the given block from the state
+    /// root of its parent, or `None` if the block doesn't exist. An error is returned if the
+    /// parent block is missing.
+    pub fn state_root_inputs(&self, block_number: BlockNumber) -> Result<Option<StateRootInputs>> {
+        let storage = self.storage.read();
+
+        let (Some(header), Some(state_updates)) =
+            (storage.block_headers.get(&block_number), storage.state_update.get(&block_number))
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(StateRootInputs {
+            block_number,
+            parent_state_root: parent_state_root(&storage, block_number)?,
+            state_root: header.state_root,
+            state_updates: state_updates.clone(),
+        }))
+    }
+
+    /// Evicts the oldest historical states until the estimated memory used by the states is under
+    /// `budget`, keeping at least the most recent historical state.
+    ///
+    /// A snapshot shared by several blocks is only counted once, and its size is only released
+    /// once the last block sharing it is evicted.
+    fn prune_to_budget(
+        &self,
+        historical_states: &mut HistoricalStates<InMemorySnapshot>,
+        budget: usize,
+    ) -> Vec<(BlockNumber, Arc<InMemorySnapshot>)> {
+        let mut handles: HashMap<*const InMemorySnapshot, usize> = HashMap::new();
+        let mut size = self.state.estimated_size();
+        for (_, state) in historical_states.iter() {
+            let count = handles.entry(Arc::as_ptr(state)).or_default();
+            if *count == 0 {
+                size += state.inner.estimated_size();
+            }
+            *count += 1;
+        }
+
+        let mut evicted = Vec::new();
+        while size > budget && historical_states.len() > 1 {
+            let Some((num, state)) = historical_states.evict_oldest() else { break };
+            let count = handles.get_mut(&Arc::as_ptr(&state)).expect("state should be counted");
+            *count -= 1;
+            if *count == 0 {
+                size -= state.inner.estimated_size();
+            }
+            evicted.push((num, state));
+        }
+
+        evicted
+    }
+
+    /// Returns the index of the transaction with the given hash within its block, or `None` if
+    /// the transaction doesn't exist.
+    pub fn transaction_index_in_block(&self, hash: TxHash) -> Result<Option<u64>> {
+        let storage = self.storage.read();
+
+        let Some(number) = storage.transaction_numbers.get(&hash) else { return Ok(None) };
+        let block_num = storage.transaction_block.get(number).expect("block num should exist");
+        let indices = storage.block_body_indices.get(block_num).expect("block should exist");
+
+        Ok(Some(number - indices.tx_offset))
+    }
+
+    /// Writes a human-readable summary of the chain to `w`, with one line per block in ascending
+    /// order containing its number, hash, transaction count, status and state root.
+    pub fn debug_dump(&self, w: &mut dyn std::io::Write) -> Result<()> {
+        let storage = self.storage.read();
+
+        let mut numbers = storage.block_hashes.keys().copied().collect::<Vec<_>>();
+        numbers.sort_unstable();
+
+        for num in numbers {
+            let hash = storage.block_hashes.get(&num).expect("block hash should exist");
+            let header = storage.block_headers.get(&num).expect("header should exist");
+            let status = storage.block_statusses.get(&num).expect("block status should exist");
+            let tx_count = storage.block_body_indices.get(&num).map_or(0, |i| i.tx_count);
+
+            writeln!(
+                w,
+                "block={num} hash={hash:#x} tx_count={tx_count} status={status:?} state_root={:#x}",
+                header.state_root
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if `ancestor` is `descendant` or one of its ancestors, ie. if it comes
+    /// before `descendant` and the blocks in between link back to it.
+    ///
+    /// An error is returned if any of the two blocks is unknown.
+    pub fn is_ancestor(
+        &self,
+        ancestor: BlockHashOrNumber,
+        descendant: BlockHashOrNumber,
+    ) -> Result<bool> {
+        let storage = self.storage.read();
+
+        let resolve = |id: BlockHashOrNumber| {
+            let num = match id {
+                BlockHashOrNumber::Num(num) => {
+                    storage.block_hashes.contains_key(&num).then_some(num)
+                }
+                BlockHashOrNumber::Hash(hash) => storage.block_numbers.get(&hash).copied(),
+            };
+            num.ok_or(InMemoryProviderError::BlockNotFound(id))
+        };
+
+        let ancestor_num = resolve(ancestor)?;
+        let descendant_num = resolve(descendant)?;
+
+        if ancestor_num > descendant_num {
+            return Ok(false);
+        }
+
+        Ok(first_broken_link(&storage, ancestor_num..=descendant_num)?.is_none())
+    }
+
+    /// Returns the number of events emitted by the transaction with the given hash, or `None` if
+    /// the transaction doesn't exist.
+    pub fn event_count_by_tx(&self, hash: TxHash) -> Result<Option<u64>> {
+        let storage = self.storage.read();
+        let count = storage
+            .transaction_numbers
+            .get(&hash)
+            .and_then(|num| storage.receipts.get(*num as usize))
+            .map(|receipt| receipt.events().len() as u64);
+        Ok(count)
+    }
+
+    /// Inserts a block without creating its historical state, which is faster and uses less
+    /// memory when the historical states are not needed, eg. for bulk imports.
+    ///
+    /// [`StateFactoryProvider::historical`] returns `None` for the blocks inserted this way, the
+    /// latest state is updated as usual.
+    pub fn insert_block_no_history(
+        &self,
+        block: SealedBlockWithStatus,
+        states: StateUpdatesWithDeclaredClasses,
+        receipts: Vec<Receipt>,
+    ) -> Result<()> {
+        self.insert_block(block, states, receipts, false)
+    }
+
+    /// Inserts a block with `state_root` as the state root of its header, regardless of the one
+    /// it was sealed with, eg. to simulate a block producer committing to a wrong state root.
+    ///
+    /// The block hash is not recomputed. If [`Self::with_state_root_validation`] is used, the
+    /// block is rejected when `state_root` differs from the computed one.
+    pub fn insert_block_with_state_root(
+        &self,
+        mut block: SealedBlockWithStatus,
+        states: StateUpdatesWithDeclaredClasses,
+        receipts: Vec<Receipt>,
+        state_root: FieldElement,
+    ) -> Result<()> {
+        block.block.header.header.state_root = state_root;
+        self.insert_block(block, states, receipts, true)
+    }
+
+    /// Replaces the blocks, the latest state and the historical states of the provider with the
+    /// ones of `new`, atomically. The existing handles to the latest state observe the new state
+    /// afterward.
+    ///
+    /// The configuration of the provider, ie. its snapshot strategy, lock strategy, memory budget,
+    /// state root validation and eviction callback, is kept. The event index is taken from `new`
+    /// along with the blocks.
+    pub fn swap_state(&self, new: InMemoryProvider) {
+        let InMemoryProvider {
+            storage: new_storage,
+            state: new_state,
+            historical_states,
+            compiled_to_class: new_compiled_to_class,
+            state_dirty: new_state_dirty,
+            ..
+        } = new;
+
+        {
+            let mut storage = self.storage.write();
+            let mut historical = self.historical_states.write();
+
+            let mut contract_storage = self.state.storage.write();
+            let mut contract_state = self.state.contract_state.write();
+            let mut compiled_class_hashes = self.state.compiled_class_hashes.write();
+            let mut sierra_classes = self.state.shared_contract_classes.sierra_classes.write();
+            let mut compiled_classes = self.state.shared_contract_classes.compiled_classes.write();
+            let mut compiled_to_class = self.compiled_to_class.write();
+
+            if let Some(index) = &self.block_index {
+                index.clear();
+            }
+
+            *storage = new_storage.into_inner();
+            *historical = historical_states.into_inner();
+
+            // the state is copied into the existing one so that its handles observe the new
+            // state, the historical states of `new` keep referring to its own classes.
+            *contract_storage = new_state.storage.read().clone();
+            *contract_state = new_state.contract_state.read().clone();
+            *compiled_class_hashes = new_state.compiled_class_hashes.read().clone();
+            *sierra_classes = new_state.shared_contract_classes.sierra_classes.read().clone();
+            *compiled_classes = new_state.shared_contract_classes.compiled_classes.read().clone();
+            *compiled_to_class = new_compiled_to_class.into_inner();
+            self.state_dirty.store(new_state_dirty.into_inner(), Ordering::SeqCst);
+
+            *self.pending_state.write() = None;
+
+            if let Some(index) = &self.block_index {
+                index.fill(&storage);
+            }
+        }
+
+        self.block_notify.notify_waiters();
+    }
+
+    /// Returns the compiled class hashes of the given classes in the latest state, in the same
+    /// order as `class_hashes`. Unknown classes are returned as `None`.
+    pub fn compiled_class_hashes(
+        &self,
+        class_hashes: &[ClassHash],
+    ) -> Result<Vec<Option<CompiledClassHash>>> {
+        let compiled_class_hashes = self.state.compiled_class_hashes.read();
+        Ok(class_hashes.iter().map(|hash| compiled_class_hashes.get(hash).copied()).collect())
+    }
+
+    /// Inserts a block, creating its historical state only if `with_history` is `true`.
+    fn insert_block(
+        &self,
+        block: SealedBlockWithStatus,
+        states: StateUpdatesWithDeclaredClasses,
+        receipts: Vec<Receipt>,
+        with_history: bool,
+    ) -> Result<()> {
+        let mut storage = self.storage.write();
+        let evicted =
+            self.insert_block_with_storage(&mut storage, block, states, receipts, with_history)?;
+        drop(storage);
+
+        self.on_snapshots_evicted(evicted);
+        self.block_notify.notify_waiters();
+
+        Ok(())
+    }
+
+    /// Inserts a block while the storage lock is held by the caller, returning the historical
+    /// states evicted to make room for its own. The caller is responsible for handing the evicted
+    /// states to [`Self::on_snapshots_evicted`] and notifying the waiters once the lock is
+    /// released.
+    fn insert_block_with_storage(
+        &self,
+        storage: &mut CacheDb<()>,
+        block: SealedBlockWithStatus,
+        states: StateUpdatesWithDeclaredClasses,
+        receipts: Vec<Receipt>,
+        with_history: bool,
+    ) -> Result<Vec<(BlockNumber, Arc<InMemorySnapshot>)>> {
+        let block_hash = block.block.header.hash;
+        let block_number = block.block.header.header.number;
+
+        self.validate_block(&block, &states, &receipts, || {
+            parent_state_root(storage, block_number)
+        })?;
+
+        let block_header = block.block.header.header;
+        let txs = block.block.body;
+
+        // create block body indices
+        let tx_count = txs.len() as u64;
+        let tx_offset = storage.transactions.len() as u64;
+        let block_body_indices = StoredBlockBodyIndices { tx_offset, tx_count };
+
+        let (txs_id, txs): (Vec<(TxNumber, TxHash)>, Vec<Tx>) = txs
+            .into_iter()
+            .enumerate()
+            .map(|(num, tx)| ((num as u64 + tx_offset, tx.hash), tx.transaction))
+            .unzip();
+
+        let txs_num = txs_id.clone().into_iter().map(|(num, hash)| (hash, num));
+        let txs_block = txs_id.clone().into_iter().map(|(num, _)| (num, block_number));
+
+        storage.latest_block_hash = block_hash;
+        storage.latest_block_number = block_number;
+
+        storage.block_numbers.insert(block_hash, block_number);
+        storage.block_hashes.insert(block_number, block_hash);
+        storage.block_headers.insert(block_number, block_header);
+        storage.block_statusses.insert(block_number, block.status);
+        storage.block_body_indices.insert(block_number, block_body_indices);
+        if !with_history {
+            storage.blocks_without_history.insert(block_number);
+        }
+
+        storage.transactions.extend(txs);
+        storage.transaction_hashes.extend(txs_id);
+        storage.transaction_numbers.extend(txs_num);
+        storage.transaction_block.extend(txs_block);
+        if let Some(index) = storage.event_index.as_mut() {
+            index.insert_block(block_number, &receipts);
+        }
+
+        storage.block_event_blooms.insert(block_number, BloomFilter::from_receipts(&receipts));
+
+        storage.receipts.extend(receipts);
+
+        for (address, slots) in &states.state_updates.storage_updates {
+            for (key, _) in slots.iter().filter(|(_, value)| **value != FieldElement::ZERO) {
+                storage.slots_first_nonzero.entry((*address, *key)).or_insert(block_number);
+            }
+        }
+
+        let is_noop = states.state_updates == StateUpdates::default()
+            && states.declared_sierra_classes.is_empty()
+            && states.declared_compiled_classes.is_empty();
+        storage.state_update.insert(block_number, states.state_updates.clone());
+
+        {
+            let mut compiled_to_class = self.compiled_to_class.write();
+            for (class_hash, compiled_hash) in &states.state_updates.declared_classes {
+                compiled_to_class.entry(*compiled_hash).or_insert(*class_hash);
+            }
+        }
+
+        self.state.insert_updates(states);
+        *self.pending_state.write() = None;
+
+        let mut historical_states = self.historical_states.write();
+
+        // the direct writes to the state are not part of the state updates replayed by the diff
+        // strategy, so a block inserted after any of them gets a full snapshot.
+        let create_snapshot = with_history
+            && match self.snapshot_strategy {
+                SnapshotStrategy::Full => true,
+                SnapshotStrategy::Diff => {
+                    self.state_dirty.load(Ordering::SeqCst)
+                        || historical_states
+                            .get_at_or_below(block_number)
+                            .map_or(true, |(base, _)| block_number - base >= DIFF_SNAPSHOT_INTERVAL)
+                }
+            };
+
+        let mut evicted = if create_snapshot {
+            // a block that doesn't change the state shares the snapshot of its parent, unless the
+            // state has been written to directly since the snapshot of the parent was created.
+            let dirty = self.state_dirty.swap(false, Ordering::SeqCst);
+            let parent = block_number.checked_sub(1).and_then(|num| historical_states.get(&num));
+            match parent.filter(|_| is_noop && !dirty).cloned() {
+                Some(parent) => historical_states.insert_shared(block_number, parent),
+                None => {
+                    let snapshot = self.state.create_snapshot();
+                    historical_states.insert(block_number, Box::new(snapshot))
+                }
+            }
+        } else {
+            Vec::new()
+        };
+
+        if let Some(budget) = self.memory_budget {
+            evicted.extend(self.prune_to_budget(&mut historical_states, budget));
+        }
+
+        // the block is only visible through the index once it is fully written.
+        if let Some(index) = &self.block_index {
+            let header = storage.block_headers[&block_number].clone();
+            index.insert(block_number, block_hash, header);
+        }
+
+        Ok(evicted)
+    }
+
+    /// Checks that a block has one receipt per transaction and, if the state root validation is
+    /// enabled, that its state root is the one computed on top of the state root returned by
+    /// `parent_state_root`.
+    fn validate_block(
+        &self,
+        block: &SealedBlockWithStatus,
+        states: &StateUpdatesWithDeclaredClasses,
+        receipts: &[Receipt],
+        parent_state_root: impl FnOnce() -> Result<FieldElement>,
+    ) -> Result<()> {
+        let number = block.block.header.header.number;
+
+        if receipts.len() != block.block.body.len() {
+            return Err(InMemoryProviderError::ReceiptCountMismatch {
+                number,
+                transactions: block.block.body.len(),
+                receipts: receipts.len(),
+            }
+            .into());
+        }
+
+        if let Some(compute_state_root) = &self.state_root_validator {
+            let expected = compute_state_root(parent_state_root()?, &states.state_updates);
+            let actual = block.block.header.header.state_root;
+            if expected != actual {
+                return Err(
+                    InMemoryProviderError::StateRootMismatch { number, expected, actual }.into()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the hashes of the classes declared in this provider but not in `other`, sorted in
+    /// ascending order.
+    pub fn declared_classes_diff(&self, other: &InMemoryProvider) -> Result<Vec<ClassHash>> {
+        let classes = &self.state.shared_contract_classes;
+        let other_classes = &other.state.shared_contract_classes;
+        if Arc::ptr_eq(classes, other_classes) {
+            return Ok(Vec::new());
+        }
+
+        // the keys of `other` are collected, and its lock released, before the classes of this
+        // provider are locked, so that the two locks are never held together.
+        let other_hashes =
+            other_classes.compiled_classes.read().keys().copied().collect::<HashSet<_>>();
+
+        let mut missing = classes
+            .compiled_classes
+            .read()
+            .keys()
+            .filter(|hash| !other_hashes.contains(hash))
+            .copied()
+            .collect::<Vec<_>>();
+        missing.sort_unstable();
+
+        Ok(missing)
+    }
+
+    /// Returns the number of the highest block accepted on L1, or `None` if no block is.
+    pub fn finalized_block_number(&self) -> Result<Option<BlockNumber>> {
+        let storage = self.storage.read();
+        let number = storage
+            .block_statusses
+            .iter()
+            .filter(|(_, status)| **status == FinalityStatus::AcceptedOnL1)
+            .map(|(num, _)| *num)
+            .max();
+        Ok(number)
+    }
+
+    /// Returns the historical state at the [finalized block](Self::finalized_block_number), or
+    /// `None` if no block is finalized yet.
+    pub fn finalized_state(&self) -> Result<Option<Box<dyn StateProvider>>> {
+        match self.finalized_block_number()? {
+            Some(num) => self.historical(BlockHashOrNumber::Num(num)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the average number of transactions and estimated bytes per block over the last
+    /// `window` blocks, up to and including the tip. The memory estimate only accounts for the
+    /// blocks data, not for the historical states.
+    pub fn growth_stats(&self, window: u64) -> Result<GrowthStats> {
+        let storage = self.storage.read();
+
+        let (mut blocks, mut txs, mut bytes) = (0u64, 0u64, 0usize);
+        if !storage.block_hashes.is_empty() && window > 0 {
+            let tip = storage.latest_block_number;
+            for num in tip.saturating_sub(window - 1)..=tip {
+                let Some(size) = storage.estimated_block_size(num) else { continue };
+                let indices = storage.block_body_indices.get(&num).expect("should exist");
+
+                blocks += 1;
+                txs += indices.tx_count;
+                bytes += size;
+            }
+        }
+
+        let average = |total: f64| if blocks == 0 { 0.0 } else { total / blocks as f64 };
+        Ok(GrowthStats {
+            blocks,
+            avg_txs_per_block: average(txs as f64),
+            avg_bytes_per_block: average(bytes as f64),
+        })
+    }
+
+    /// Returns the signature of the transaction with the given hash, or `None` if the transaction
+    /// doesn't exist or is an L1 handler transaction, which has no signature.
+    pub fn transaction_signature(&self, hash: TxHash) -> Result<Option<Vec<FieldElement>>> {
+        let storage = self.storage.read();
+
+        let Some(num) = storage.transaction_numbers.get(&hash) else { return Ok(None) };
+        let signature = match storage.transactions.get(*num as usize) {
+            Some(Tx::Invoke(tx)) => Some(tx.signature.clone()),
+            Some(Tx::Declare(DeclareTx::V1(tx))) => Some(tx.signature.clone()),
+            Some(Tx::Declare(DeclareTx::V2(tx))) => Some(tx.signature.clone()),
+            Some(Tx::DeployAccount(tx)) => Some(tx.signature.clone()),
+            Some(Tx::L1Handler(_)) | None => None,
+        };
+
+        Ok(signature)
+    }
+
+    /// Returns the complete latest state, read atomically.
+    ///
+    /// The whole state is copied, so this uses as much memory as the latest state itself,
+    /// excluding the class definitions. To bound that cost, an error is returned if the state
+    /// has more than [`FULL_STATE_MAX_ENTRIES`] entries, counting contracts, storage slots and
+    /// classes.
+    pub fn full_latest_state(&self) -> Result<FullState> {
+        let storage = self.state.storage.read();
+        let contract_state = self.state.contract_state.read();
+        let compiled_class_hashes = self.state.compiled_class_hashes.read();
+        let compiled_classes = self.state.shared_contract_classes.compiled_classes.read();
+
+        let entries = storage.values().map(HashMap::len).sum::<usize>()
+            + contract_state.len()
+            + compiled_class_hashes.len()
+            + compiled_classes.len();
+
+        if entries > FULL_STATE_MAX_ENTRIES {
+            let limit = FULL_STATE_MAX_ENTRIES;
+            return Err(InMemoryProviderError::StateTooLarge { entries, limit }.into());
+        }
+
+        let mut declared_classes = compiled_classes.keys().copied().collect::<Vec<_>>();
+        declared_classes.sort_unstable();
+
+        Ok(FullState {
+            contracts: contract_state.clone(),
+            storage: storage.clone(),
+            compiled_class_hashes: compiled_class_hashes.clone(),
+            declared_classes,
+        })
+    }
+
+    /// Returns the first block in which the given storage slot was set to a non-zero value, or
+    /// `None` if it never was. Only the state updates of the blocks are taken into account, not
+    /// the values set directly through [`StateWriter`].
+    pub fn slot_first_nonzero_at(
+        &self,
+        address: ContractAddress,
+        key: StorageKey,
+    ) -> Result<Option<BlockNumber>> {
+        Ok(self.storage.read().slots_first_nonzero.get(&(address, key)).copied())
+    }
+
+    /// Returns all the events emitted in the given block in emission order, ie. ordered by
+    /// transaction and then by event, or `None` if the block doesn't exist.
+    pub fn block_events(&self, block_number: BlockNumber) -> Result<Option<Vec<EmittedEvent>>> {
+        let storage = self.storage.read();
+
+        let Some(indices) = storage.block_body_indices.get(&block_number) else { return Ok(None) };
+
+        let mut events = Vec::new();
+        for (transaction_index, tx_num) in Range::<u64>::from(indices.clone()).enumerate() {
+            let transaction_hash =
+                *storage.transaction_hashes.get(&tx_num).expect("tx hash should exist");
+
+            let receipt = &storage.receipts[tx_num as usize];
+            for (event_index, event) in receipt.events().iter().enumerate() {
+                events.push(EmittedEvent {
+                    transaction_hash,
+                    transaction_index: transaction_index as u64,
+                    event_index: event_index as u64,
+                    event: event.clone(),
+                });
+            }
+        }
+
+        Ok(Some(events))
+    }
+
+    /// Returns the blocks in the given range along with their hash and status, as they were
+    /// inserted. Missing blocks are skipped.
+    pub fn sealed_blocks_in_range(
+        &self,
+        range: RangeInclusive<u64>,
+    ) -> Result<Vec<SealedBlockWithStatus>> {
+        let storage = self.storage.read();
+
+        let mut blocks = Vec::new();
+        for num in range {
+            let Some(body) = storage.block_body(num) else { continue };
+
+            let hash = *storage.block_hashes.get(&num).expect("block hash should exist");
+            let header = storage.block_headers.get(&num).cloned().expect("header should exist");
+            let status = *storage.block_statusses.get(&num).expect("block status should exist");
+
+            blocks.push(SealedBlockWithStatus {
+                block: SealedBlock { header: SealedHeader { hash, header }, body },
+                status,
+            });
+        }
+
+        Ok(blocks)
+    }
+
+    /// Returns the blocks with the given numbers, resolved under a single lock, in the same order
+    /// as `nums`. Missing blocks are returned as `None`.
+    pub fn blocks_by_numbers(&self, nums: &[BlockNumber]) -> Result<Vec<Option<Block>>> {
+        let storage = self.storage.read();
+
+        let blocks = nums
+            .iter()
+            .map(|num| {
+                let header = storage.block_headers.get(num).cloned()?;
+                let body = storage.block_body(*num).unwrap_or_default();
+                Some(Block { header, body })
+            })
+            .collect();
+
+        Ok(blocks)
+    }
+
+    /// Returns the hash of the transactions in the given range of blocks that deployed a
+    /// contract, along with the address of the deployed contract, in order. Missing blocks and
+    /// reverted transactions are skipped.
+    ///
+    /// Two forms of deployment are covered: `DeployAccount` transactions, and `Invoke`
+    /// transactions deploying through the Universal Deployer Contract, which are detected by a
+    /// `ContractDeployed` event emitted from [`UDC_ADDRESS`] whose contract is also part of the
+    /// contract updates of the block. Contracts deployed with the `deploy` syscall by other
+    /// contracts are not covered.
+    pub fn deploy_transactions_in_range(
+        &self,
+        range: RangeInclusive<u64>,
+    ) -> Result<Vec<(TxHash, ContractAddress)>> {
+        let storage = self.storage.read();
+
+        let mut deploys = Vec::new();
+        for num in range {
+            let Some(indices) = storage.block_body_indices.get(&num) else { continue };
+            let contract_updates = storage.state_update.get(&num).map(|s| &s.contract_updates);
+
+            for tx_num in Range::<u64>::from(indices.clone()) {
+                let receipt = &storage.receipts[tx_num as usize];
+                if receipt.is_reverted() {
+                    continue;
+                }
+
+                let addresses = match &storage.transactions[tx_num as usize] {
+                    Tx::DeployAccount(tx) => vec![tx.contract_address],
+                    Tx::Invoke(_) => receipt
+                        .events()
+                        .iter()
+                        .filter(|e| {
+                            e.from_address == UDC_ADDRESS
+                                && e.keys.first() == Some(&selector!("ContractDeployed"))
+                        })
+                        .filter_map(|e| e.data.first().map(|address| (*address).into()))
+                        .filter(|address| {
+                            contract_updates.map_or(false, |updates| updates.contains_key(address))
+                        })
+                        .collect(),
+                    _ => continue,
+                };
+
+                let hash = *storage.transaction_hashes.get(&tx_num).expect("tx hash should exist");
+                deploys.extend(addresses.into_iter().map(|address| (hash, address)));
+            }
+        }
 
-use anyhow::Result;
-use katana_db::models::block::StoredBlockBodyIndices;
-use katana_primitives::block::{
-    Block, BlockHash, BlockHashOrNumber, BlockNumber, BlockWithTxHashes, FinalityStatus, Header,
-    SealedBlockWithStatus,
-};
-use katana_primitives::contract::{
-    ClassHash, CompiledClassHash, CompiledContractClass, ContractAddress, SierraClass,
-};
-use katana_primitives::receipt::Receipt;
-use katana_primitives::state::{StateUpdates, StateUpdatesWithDeclaredClasses};
-use katana_primitives::transaction::{Tx, TxHash, TxNumber, TxWithHash};
-use parking_lot::RwLock;
+        Ok(deploys)
+    }
 
-use self::cache::CacheDb;
-use self::state::{HistoricalStates, InMemoryStateDb, LatestStateProvider};
-use crate::traits::block::{
-    BlockHashProvider, BlockNumberProvider, BlockProvider, BlockStatusProvider, BlockWriter,
-    HeaderProvider,
-};
-use crate::traits::contract::ContractClassWriter;
-use crate::traits::state::{StateFactoryProvider, StateProvider, StateRootProvider, StateWriter};
-use crate::traits::state_update::StateUpdateProvider;
-use crate::traits::transaction::{
-    ReceiptProvider, TransactionProvider, TransactionStatusProvider, TransactionsProviderExt,
-};
+    /// Returns the historical states of all the blocks in the given range, acquiring the locks
+    /// only once for the whole range. Blocks whose historical state is not available, eg. because
+    /// it has been pruned, are omitted.
+    pub fn historical_states_in_range(
+        &self,
+        range: RangeInclusive<u64>,
+    ) -> Result<Vec<(BlockNumber, Box<dyn StateProvider>)>> {
+        let storage = self.storage.read();
+        let historical_states = self.historical_states.read();
+
+        let states = range
+            .filter_map(|num| {
+                self.historical_state(&storage, &historical_states, num)
+                    .map(|state| (num, Box::new(state) as Box<dyn StateProvider>))
+            })
+            .collect();
 
-pub struct InMemoryProvider {
-    storage: RwLock<CacheDb<()>>,
-    state: Arc<InMemoryStateDb>,
-    historical_states: RwLock<HistoricalStates>,
-}
+        Ok(states)
+    }
 
-impl InMemoryProvider {
-    pub fn new() -> Self {
-        let storage = RwLock::new(CacheDb::new(()));
-        let state = Arc::new(InMemoryStateDb::new(()));
-        let historical_states = RwLock::new(HistoricalStates::default());
-        Self { storage, state, historical_states }
+    /// Returns the class hash, nonce and storage slot count of a contract in the latest state,
+    /// read atomically. All the fields are zero if the contract is not deployed.
+    pub fn account_snapshot(&self, address: ContractAddress) -> Result<AccountSnapshot> {
+        let storage = self.state.storage.read();
+        let contract_state = self.state.contract_state.read();
+
+        let info = contract_state.get(&address).copied().unwrap_or_default();
+        Ok(AccountSnapshot {
+            class_hash: info.class_hash,
+            nonce: info.nonce,
+            storage_slot_count: storage.get(&address).map_or(0, count_set_slots),
+        })
     }
-}
 
-impl Default for InMemoryProvider {
-    fn default() -> Self {
-        Self::new()
+    /// Returns the hashes of all the transactions of the chain, ordered by transaction number.
+    ///
+    /// This sorts and copies every transaction hash while the storage lock is held, so it is
+    /// expensive on long chains. Prefer
+    /// [`TransactionsProviderExt::transaction_hashes_in_range`] to page through them instead.
+    pub fn all_transaction_hashes(&self) -> Result<Vec<TxHash>> {
+        let storage = self.storage.read();
+
+        let mut hashes = storage.transaction_hashes.iter().collect::<Vec<_>>();
+        hashes.sort_unstable_by_key(|(num, _)| **num);
+
+        Ok(hashes.into_iter().map(|(_, hash)| *hash).collect())
+    }
+
+    /// Reverts the chain to `block_number`, removing all the blocks above it along with their
+    /// transactions, receipts and state updates, and restoring the latest state to the state at
+    /// `block_number`.
+    ///
+    /// The removed blocks are returned with their state updates, declared classes and receipts,
+    /// in descending order, so that they can be inspected or inserted back, eg. when handling a
+    /// reorg. The eviction callback is not invoked for the historical states of the removed
+    /// blocks.
+    ///
+    /// The classes declared in the removed blocks are returned as well, but their definitions are
+    /// kept in the class store, which is shared with the historical states, and only their
+    /// declarations are reverted.
+    ///
+    /// An error is returned before anything is removed if `block_number` doesn't exist
+    /// ([`InMemoryProviderError::BlockNotFound`]), if its historical state is not available
+    /// ([`InMemoryProviderError::MissingHistoricalState`]), or if the transactions of the removed
+    /// blocks are not the last ones stored ([`InMemoryProviderError::TransactionsNotAtTip`]).
+    pub fn revert_block_returning(
+        &self,
+        block_number: BlockNumber,
+    ) -> Result<Vec<(SealedBlockWithStatus, StateUpdatesWithDeclaredClasses, Vec<Receipt>)>> {
+        let mut storage = self.storage.write();
+        let mut historical_states = self.historical_states.write();
+
+        if !storage.block_hashes.contains_key(&block_number) {
+            let id = BlockHashOrNumber::Num(block_number);
+            return Err(InMemoryProviderError::BlockNotFound(id).into());
+        }
+
+        let Some(state) = self.historical_state(&storage, &historical_states, block_number) else {
+            return Err(InMemoryProviderError::MissingHistoricalState(block_number).into());
+        };
+
+        let reverted = block_number + 1..=storage.latest_block_number;
+
+        let mut tx_end = storage.transactions.len() as u64;
+        for num in reverted.clone().rev() {
+            let Some(indices) = storage.block_body_indices.get(&num) else {
+                return Err(InMemoryProviderError::MissingBlock(num).into());
+            };
+            if indices.tx_offset + indices.tx_count != tx_end {
+                return Err(InMemoryProviderError::TransactionsNotAtTip(num).into());
+            }
+            tx_end = indices.tx_offset;
+        }
+
+        let mut contract_storage = self.state.storage.write();
+        let mut contract_state = self.state.contract_state.write();
+        let mut compiled_class_hashes = self.state.compiled_class_hashes.write();
+        let sierra_classes = self.state.shared_contract_classes.sierra_classes.read();
+        let compiled_classes = self.state.shared_contract_classes.compiled_classes.read();
+
+        // the blocks are removed from the index before any of their data is.
+        if let Some(index) = &self.block_index {
+            for num in reverted.clone() {
+                index.remove(num, storage.block_hashes[&num]);
+            }
+        }
+
+        let mut removed = Vec::new();
+        for num in reverted.rev() {
+            let indices = storage.block_body_indices.remove(&num).expect("should exist");
+            let Range { start, end } = Range::<u64>::from(indices);
+
+            let receipts = storage.receipts.split_off(start as usize);
+            let txs = storage.transactions.split_off(start as usize);
+            let body = txs
+                .into_iter()
+                .zip(start..end)
+                .map(|(transaction, tx_num)| {
+                    let hash =
+                        storage.transaction_hashes.remove(&tx_num).expect("tx hash should exist");
+                    storage.transaction_numbers.remove(&hash);
+                    storage.transaction_block.remove(&tx_num);
+                    TxWithHash { hash, transaction }
+                })
+                .collect::<Vec<_>>();
+
+            let hash = storage.block_hashes.remove(&num).expect("block hash should exist");
+            storage.block_numbers.remove(&hash);
+            let header = storage.block_headers.remove(&num).expect("header should exist");
+            let status = storage.block_statusses.remove(&num).expect("block status should exist");
+            storage.block_event_blooms.remove(&num);
+            storage.blocks_without_history.remove(&num);
+            let state_updates = storage.state_update.remove(&num).unwrap_or_default();
+
+            // legacy classes are not part of the declared classes of the state updates, so they
+            // are looked up from the declare transactions instead.
+            let declared = state_updates
+                .declared_classes
+                .keys()
+                .copied()
+                .chain(body.iter().filter_map(|tx| match &tx.transaction {
+                    Tx::Declare(tx) => Some(tx.class_hash()),
+                    _ => None,
+                }))
+                .collect::<Vec<_>>();
+
+            let mut declared_sierra_classes = HashMap::new();
+            let mut declared_compiled_classes = HashMap::new();
+            for hash in declared {
+                if let Some(class) = compiled_classes.get(&hash) {
+                    declared_compiled_classes.insert(hash, class.clone());
+                }
+                if let Some(class) = sierra_classes.get(&hash) {
+                    declared_sierra_classes.insert(hash, class.clone());
+                }
+            }
+
+            let block = SealedBlockWithStatus {
+                block: SealedBlock { header: SealedHeader { hash, header }, body },
+                status,
+            };
+            let states = StateUpdatesWithDeclaredClasses {
+                state_updates,
+                declared_sierra_classes,
+                declared_compiled_classes,
+            };
+            removed.push((block, states, receipts));
+        }
+
+        storage.slots_first_nonzero.retain(|_, num| *num <= block_number);
+        if let Some(index) = storage.event_index.as_mut() {
+            index.remove_blocks_above(block_number);
+        }
+
+        storage.latest_block_number = block_number;
+        storage.latest_block_hash = storage.block_hashes[&block_number];
+        historical_states.remove_above(block_number);
+
+        *contract_storage = state.inner.storage.clone();
+        *contract_state = state.inner.contract_state.clone();
+        *compiled_class_hashes = state.inner.compiled_class_hashes.clone();
+        self.compiled_to_class.write().retain(|compiled_hash, class_hash| {
+            compiled_class_hashes.get(class_hash) == Some(compiled_hash)
+        });
+        // the latest state now matches the historical state of `block_number`.
+        self.state_dirty.store(false, Ordering::SeqCst);
+        *self.pending_state.write() = None;
+
+        Ok(removed)
+    }
+
+    /// Returns the hash of the class declared with the given compiled class hash, or `None` if no
+    /// class is. If several classes were declared with the same compiled class hash, the first
+    /// declared one is returned.
+    pub fn class_hash_by_compiled(&self, compiled: CompiledClassHash) -> Result<Option<ClassHash>> {
+        Ok(self.compiled_to_class.read().get(&compiled).copied())
+    }
+
+    /// Returns the latest block number like [`BlockNumberProvider::latest_number`], or `None` if
+    /// there are no blocks, failing with [`InMemoryProviderError::LockTimeout`] instead of
+    /// blocking if the storage lock can't be acquired within `dur`, eg. during a long insert.
+    pub fn try_latest_number_timeout(&self, dur: Duration) -> Result<Option<BlockNumber>> {
+        let Some(storage) = self.storage.try_read_for(dur) else {
+            return Err(InMemoryProviderError::LockTimeout(dur).into());
+        };
+        Ok(Some(storage.latest_block_number).filter(|_| !storage.block_hashes.is_empty()))
+    }
+
+    /// Returns the transaction hashes that are stored for more than one transaction number, in
+    /// ascending order. This should always be empty, anything returned points to a bug in the
+    /// block insertion.
+    pub fn find_duplicate_transaction_hashes(&self) -> Result<Vec<TxHash>> {
+        let storage = self.storage.read();
+
+        let mut seen = HashSet::new();
+        let mut duplicates = storage
+            .transaction_hashes
+            .values()
+            .filter(|hash| !seen.insert(**hash))
+            .copied()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        duplicates.sort_unstable();
+
+        Ok(duplicates)
+    }
+
+    /// Returns the state updates of the latest block, or `None` if there are no blocks.
+    pub fn latest_state_update(&self) -> Result<Option<StateUpdates>> {
+        let storage = self.storage.read();
+        Ok(storage.state_update.get(&storage.latest_block_number).cloned())
+    }
+
+    /// Returns the addresses of the contracts whose class is `class_hash` in the latest state, in
+    /// ascending order.
+    ///
+    /// This scans every contract of the latest state, so its cost grows with the number of
+    /// deployed contracts.
+    pub fn contracts_with_class(&self, class_hash: ClassHash) -> Result<Vec<ContractAddress>> {
+        let contract_state = self.state.contract_state.read();
+
+        let mut contracts = contract_state
+            .iter()
+            .filter(|(_, info)| info.class_hash == class_hash)
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        contracts.sort_unstable();
+
+        Ok(contracts)
+    }
+
+    /// Returns the storage keys of `address` changed in the given block, in ascending order.
+    ///
+    /// An error is returned if the block doesn't exist.
+    pub fn contract_storage_keys_changed_in_block(
+        &self,
+        address: ContractAddress,
+        block_number: BlockNumber,
+    ) -> Result<Vec<StorageKey>> {
+        let storage = self.storage.read();
+
+        let Some(state_updates) = storage.state_update.get(&block_number) else {
+            let id = BlockHashOrNumber::Num(block_number);
+            return Err(InMemoryProviderError::BlockNotFound(id).into());
+        };
+
+        let mut keys = state_updates
+            .storage_updates
+            .get(&address)
+            .map(|slots| slots.keys().copied().collect::<Vec<_>>())
+            .unwrap_or_default();
+        keys.sort_unstable();
+
+        Ok(keys)
+    }
+
+    /// Returns the timestamp of every block in the given range, without cloning their headers.
+    /// Missing blocks are skipped.
+    pub fn block_timestamps_in_range(
+        &self,
+        range: RangeInclusive<u64>,
+    ) -> Result<Vec<(BlockNumber, u64)>> {
+        let storage = self.storage.read();
+        let timestamps = range
+            .filter_map(|num| storage.block_headers.get(&num).map(|h| (num, h.timestamp)))
+            .collect();
+        Ok(timestamps)
+    }
+
+    /// Returns up to the `n` last transactions of a block, starting from the last one, or `None`
+    /// if the block doesn't exist.
+    pub fn last_transactions_of_block(
+        &self,
+        block_id: BlockHashOrNumber,
+        n: usize,
+    ) -> Result<Option<Vec<TxWithHash>>> {
+        let storage = self.storage.read();
+
+        let block_num = match block_id {
+            BlockHashOrNumber::Num(num) => Some(num),
+            BlockHashOrNumber::Hash(hash) => storage.block_numbers.get(&hash).copied(),
+        };
+
+        let Some(indices) = block_num.and_then(|num| storage.block_body_indices.get(&num)) else {
+            return Ok(None);
+        };
+
+        let txs = Range::<u64>::from(indices.clone())
+            .rev()
+            .take(n)
+            .map(|tx_num| {
+                let hash = *storage.transaction_hashes.get(&tx_num).expect("tx hash should exist");
+                let transaction = storage.transactions[tx_num as usize].clone();
+                TxWithHash { hash, transaction }
+            })
+            .collect();
+
+        Ok(Some(txs))
+    }
+
+    /// Hands all the data of the provider to `sink`, one [`PersistRecord`] at a time, eg. to
+    /// persist it to another storage on shutdown. The data is not removed from the provider.
+    ///
+    /// The blocks are emitted in ascending order, each as its header, then every transaction
+    /// followed by its receipt, then its state updates. The classes follow, in ascending order of
+    /// class hash. The first error returned by `sink` aborts the drain and is returned.
+    ///
+    /// The storage and class locks are held for the whole drain, so `sink` must not write to the
+    /// provider.
+    pub fn drain_to<F>(&self, mut sink: F) -> Result<()>
+    where
+        F: FnMut(PersistRecord) -> Result<()>,
+    {
+        let storage = self.storage.read();
+        let compiled_class_hashes = self.state.compiled_class_hashes.read();
+        let sierra_classes = self.state.shared_contract_classes.sierra_classes.read();
+        let compiled_classes = self.state.shared_contract_classes.compiled_classes.read();
+
+        let mut blocks = storage.block_headers.keys().copied().collect::<Vec<_>>();
+        blocks.sort_unstable();
+
+        for num in blocks {
+            sink(PersistRecord::Header {
+                hash: *storage.block_hashes.get(&num).expect("block hash should exist"),
+                status: *storage.block_statusses.get(&num).expect("block status should exist"),
+                header: storage.block_headers.get(&num).cloned().expect("header should exist"),
+            })?;
+
+            if let Some(indices) = storage.block_body_indices.get(&num) {
+                for tx_num in Range::<u64>::from(indices.clone()) {
+                    let hash =
+                        *storage.transaction_hashes.get(&tx_num).expect("tx hash should exist");
+                    let transaction = storage.transactions[tx_num as usize].clone();
+                    sink(PersistRecord::Transaction {
+                        tx_number: tx_num,
+                        transaction: TxWithHash { hash, transaction },
+                    })?;
+
+                    let receipt = storage.receipts[tx_num as usize].clone();
+                    sink(PersistRecord::Receipt { tx_number: tx_num, receipt })?;
+                }
+            }
+
+            let state_updates = storage.state_update.get(&num).cloned().unwrap_or_default();
+            sink(PersistRecord::StateUpdate { block_number: num, state_updates })?;
+        }
+
+        let mut classes = compiled_classes.iter().collect::<Vec<_>>();
+        classes.sort_unstable_by_key(|(hash, _)| **hash);
+
+        for (hash, class) in classes {
+            sink(PersistRecord::Class {
+                class_hash: *hash,
+                compiled_class_hash: compiled_class_hashes.get(hash).copied(),
+                compiled_class: class.clone(),
+                sierra_class: sierra_classes.get(hash).cloned(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the historical state of the given block has been evicted, ie. the block
+    /// is below the oldest historical state still stored after an eviction. The bodies of the
+    /// blocks are never pruned.
+    ///
+    /// Blocks inserted with [`Self::insert_block_no_history`] are not considered pruned.
+    pub fn is_block_pruned(&self, block_number: BlockNumber) -> Result<bool> {
+        let floor = self.historical_states.read().pruned_floor();
+        Ok(floor.is_some_and(|floor| block_number < floor))
+    }
+
+    /// Returns the execution status and the finality status of the block of each transaction,
+    /// resolved under a single lock, in the same order as `hashes`. Unknown transactions are
+    /// returned as `None`.
+    pub fn transaction_statuses(
+        &self,
+        hashes: &[TxHash],
+    ) -> Result<Vec<Option<(ExecutionStatus, FinalityStatus)>>> {
+        let storage = self.storage.read();
+
+        let statuses = hashes
+            .iter()
+            .map(|hash| {
+                let tx_num = *storage.transaction_numbers.get(hash)?;
+                let block_num = storage.transaction_block.get(&tx_num)?;
+                let finality = *storage.block_statusses.get(block_num)?;
+
+                let execution = if storage.receipts[tx_num as usize].is_reverted() {
+                    ExecutionStatus::Reverted
+                } else {
+                    ExecutionStatus::Succeeded
+                };
+
+                Some((execution, finality))
+            })
+            .collect();
+
+        Ok(statuses)
+    }
+
+    /// Returns the root of the Merkle tree whose leaves are the hashes of the blocks in the given
+    /// range, in order, or zero if the range is empty.
+    ///
+    /// Each node is the Pedersen hash of its two children. A node without a sibling, ie. the
+    /// last node of a level with an odd number of nodes, is moved up to the next level as is.
+    /// The range must be contiguous, an error is returned if any of the blocks in the range is
+    /// missing.
+    pub fn block_hash_root(&self, range: RangeInclusive<u64>) -> Result<FieldElement> {
+        let storage = self.storage.read();
+
+        let mut nodes = range
+            .map(|num| match storage.block_hashes.get(&num) {
+                Some(hash) => Ok(*hash),
+                None => Err(InMemoryProviderError::MissingBlock(num)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if nodes.is_empty() {
+            return Ok(FieldElement::ZERO);
+        }
+
+        while nodes.len() > 1 {
+            nodes = nodes
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => pedersen_hash(left, right),
+                    [node] => *node,
+                    _ => unreachable!("chunks are of at most two nodes"),
+                })
+                .collect();
+        }
+
+        Ok(nodes[0])
+    }
+
+    /// Returns the changes made to a contract by each block in the given range that touched it,
+    /// ie. its nonce, storage and class hash updates. The declared classes are not included.
+    /// Missing blocks and blocks that didn't change the contract are skipped.
+    pub fn contract_diffs_in_range(
+        &self,
+        address: ContractAddress,
+        range: RangeInclusive<u64>,
+    ) -> Result<Vec<(BlockNumber, StateUpdates)>> {
+        let storage = self.storage.read();
+
+        let mut diffs = Vec::new();
+        for num in range {
+            let Some(state_updates) = storage.state_update.get(&num) else { continue };
+
+            let mut diff = StateUpdates::default();
+            if let Some(nonce) = state_updates.nonce_updates.get(&address) {
+                diff.nonce_updates.insert(address, *nonce);
+            }
+            if let Some(slots) = state_updates.storage_updates.get(&address) {
+                diff.storage_updates.insert(address, slots.clone());
+            }
+            if let Some(class_hash) = state_updates.contract_updates.get(&address) {
+                diff.contract_updates.insert(address, *class_hash);
+            }
+
+            if diff != StateUpdates::default() {
+                diffs.push((num, diff));
+            }
+        }
+
+        Ok(diffs)
+    }
+
+    /// Returns the hash of the transaction that last modified the nonce, storage or class hash of
+    /// a contract, or `None` if no block changed it.
+    ///
+    /// The state changes are only tracked per block, so this is an approximation: the last
+    /// transaction of the most recent block that changed the contract is returned, even though
+    /// an earlier transaction of that block may have made the change. `None` is also returned if
+    /// that block has no transactions, eg. for the genesis block.
+    pub fn last_modifying_tx(&self, address: ContractAddress) -> Result<Option<TxHash>> {
+        let storage = self.storage.read();
+
+        let block_num = storage
+            .state_update
+            .iter()
+            .filter(|(_, updates)| {
+                updates.nonce_updates.contains_key(&address)
+                    || updates.storage_updates.contains_key(&address)
+                    || updates.contract_updates.contains_key(&address)
+            })
+            .map(|(num, _)| *num)
+            .max();
+
+        let Some(indices) = block_num.and_then(|num| storage.block_body_indices.get(&num)) else {
+            return Ok(None);
+        };
+
+        let last_tx = Range::<u64>::from(indices.clone()).last();
+        Ok(last_tx.map(|num| *storage.transaction_hashes.get(&num).expect("tx hash should exist")))
+    }
+
+    /// Returns a block along with the receipts of its transactions, in the same order, read under
+    /// a single lock so that both are consistent. Returns `None` if the block doesn't exist.
+    pub fn block_with_receipts(
+        &self,
+        id: BlockHashOrNumber,
+    ) -> Result<Option<(Block, Vec<Receipt>)>> {
+        let storage = self.storage.read();
+
+        let block_num = match id {
+            BlockHashOrNumber::Num(num) => Some(num),
+            BlockHashOrNumber::Hash(hash) => storage.block_numbers.get(&hash).copied(),
+        };
+
+        let Some((num, header)) =
+            block_num.and_then(|num| storage.block_headers.get(&num).map(|header| (num, header)))
+        else {
+            return Ok(None);
+        };
+
+        let body = storage.block_body(num).unwrap_or_default();
+        let receipts = match storage.block_body_indices.get(&num) {
+            Some(indices) => {
+                let Range { start, end } = Range::<u64>::from(indices.clone());
+                storage.receipts[start as usize..end as usize].to_vec()
+            }
+            None => Vec::new(),
+        };
+
+        Ok(Some((Block { header: header.clone(), body }, receipts)))
+    }
+
+    /// Returns the historical state at the given block, reconstructing it from the nearest full
+    /// snapshot if the [`SnapshotStrategy::Diff`] strategy is used.
+    fn historical_state(
+        &self,
+        storage: &CacheDb<()>,
+        historical_states: &HistoricalStates<InMemorySnapshot>,
+        block_num: BlockNumber,
+    ) -> Option<Arc<InMemorySnapshot>> {
+        if storage.blocks_without_history.contains(&block_num) {
+            return None;
+        }
+
+        match self.snapshot_strategy {
+            SnapshotStrategy::Full => historical_states.get(&block_num).cloned(),
+
+            SnapshotStrategy::Diff => {
+                let (base_num, base) = historical_states.get_at_or_below(block_num)?;
+                if base_num == block_num {
+                    return Some(Arc::clone(base));
+                }
+
+                let mut state = InMemorySnapshot::clone(base);
+                for num in base_num + 1..=block_num {
+                    state.inner.apply_state_updates(storage.state_update.get(&num)?);
+                }
+
+                Some(Arc::new(state))
+            }
+        }
     }
 }
 
@@ -58,6 +2135,9 @@ impl BlockHashProvider for InMemoryProvider {
     }
 
     fn block_hash_by_num(&self, num: BlockNumber) -> Result<Option<BlockHash>> {
+        if let Some(index) = &self.block_index {
+            return Ok(index.hashes.get(&num));
+        }
         Ok(self.storage.read().block_hashes.get(&num).cloned())
     }
 }
@@ -68,12 +2148,23 @@ impl BlockNumberProvider for InMemoryProvider {
     }
 
     fn block_number_by_hash(&self, hash: BlockHash) -> Result<Option<BlockNumber>> {
+        if let Some(index) = &self.block_index {
+            return Ok(index.numbers.get(&hash));
+        }
         Ok(self.storage.read().block_numbers.get(&hash).cloned())
     }
 }
 
 impl HeaderProvider for InMemoryProvider {
     fn header(&self, id: katana_primitives::block::BlockHashOrNumber) -> Result<Option<Header>> {
+        if let Some(index) = &self.block_index {
+            let num = match id {
+                BlockHashOrNumber::Num(num) => Some(num),
+                BlockHashOrNumber::Hash(hash) => index.numbers.get(&hash),
+            };
+            return Ok(num.and_then(|num| index.headers.get(&num)));
+        }
+
         match id {
             katana_primitives::block::BlockHashOrNumber::Num(num) => {
                 Ok(self.storage.read().block_headers.get(&num).cloned())
@@ -357,10 +2448,8 @@ impl StateFactoryProvider for InMemoryProvider {
         };
 
         let provider @ Some(_) = block_num.and_then(|num| {
-            self.historical_states
-                .read()
-                .get(&num)
-                .cloned()
+            let storage = self.storage.read();
+            self.historical_state(&storage, &self.historical_states.read(), num)
                 .map(|provider| Box::new(provider) as Box<dyn StateProvider>)
         }) else {
             return Ok(None);
@@ -389,62 +2478,20 @@ impl BlockWriter for InMemoryProvider {
         states: StateUpdatesWithDeclaredClasses,
         receipts: Vec<Receipt>,
     ) -> Result<()> {
-        let mut storage = self.storage.write();
-
-        let block_hash = block.block.header.hash;
-        let block_number = block.block.header.header.number;
-
-        let block_header = block.block.header.header;
-        let txs = block.block.body;
-
-        // create block body indices
-        let tx_count = txs.len() as u64;
-        let tx_offset = storage.transactions.len() as u64;
-        let block_body_indices = StoredBlockBodyIndices { tx_offset, tx_count };
-
-        let (txs_id, txs): (Vec<(TxNumber, TxHash)>, Vec<Tx>) = txs
-            .into_iter()
-            .enumerate()
-            .map(|(num, tx)| ((num as u64 + tx_offset, tx.hash), tx.transaction))
-            .unzip();
-
-        let txs_num = txs_id.clone().into_iter().map(|(num, hash)| (hash, num));
-        let txs_block = txs_id.clone().into_iter().map(|(num, _)| (num, block_number));
-
-        storage.latest_block_hash = block_hash;
-        storage.latest_block_number = block_number;
-
-        storage.block_numbers.insert(block_hash, block_number);
-        storage.block_hashes.insert(block_number, block_hash);
-        storage.block_headers.insert(block_number, block_header);
-        storage.block_statusses.insert(block_number, block.status);
-        storage.block_body_indices.insert(block_number, block_body_indices);
-
-        storage.transactions.extend(txs);
-        storage.transaction_hashes.extend(txs_id);
-        storage.transaction_numbers.extend(txs_num);
-        storage.transaction_block.extend(txs_block);
-        storage.receipts.extend(receipts);
-
-        storage.state_update.insert(block_number, states.state_updates.clone());
-
-        self.state.insert_updates(states);
-
-        let snapshot = self.state.create_snapshot();
-        self.historical_states.write().insert(block_number, Box::new(snapshot));
-
-        Ok(())
+        self.insert_block(block, states, receipts, true)
     }
 }
 
 impl ContractClassWriter for InMemoryProvider {
     fn set_class(&self, hash: ClassHash, class: CompiledContractClass) -> Result<()> {
         self.state.shared_contract_classes.compiled_classes.write().insert(hash, class);
+        self.state_dirty.store(true, Ordering::SeqCst);
         Ok(())
     }
 
     fn set_sierra_class(&self, hash: ClassHash, sierra: SierraClass) -> Result<()> {
         self.state.shared_contract_classes.sierra_classes.write().insert(hash, sierra);
+        self.state_dirty.store(true, Ordering::SeqCst);
         Ok(())
     }
 
@@ -454,6 +2501,8 @@ impl ContractClassWriter for InMemoryProvider {
         compiled_hash: CompiledClassHash,
     ) -> Result<()> {
         self.state.compiled_class_hashes.write().insert(hash, compiled_hash);
+        self.compiled_to_class.write().entry(compiled_hash).or_insert(hash);
+        self.state_dirty.store(true, Ordering::SeqCst);
         Ok(())
     }
 }
@@ -466,6 +2515,7 @@ impl StateWriter for InMemoryProvider {
         storage_value: katana_primitives::contract::StorageValue,
     ) -> Result<()> {
         self.state.storage.write().entry(address).or_default().insert(storage_key, storage_value);
+        self.state_dirty.store(true, Ordering::SeqCst);
         Ok(())
     }
 
@@ -475,6 +2525,7 @@ impl StateWriter for InMemoryProvider {
         class_hash: ClassHash,
     ) -> Result<()> {
         self.state.contract_state.write().entry(address).or_default().class_hash = class_hash;
+        self.state_dirty.store(true, Ordering::SeqCst);
         Ok(())
     }
 
@@ -484,6 +2535,46 @@ impl StateWriter for InMemoryProvider {
         nonce: katana_primitives::contract::Nonce,
     ) -> Result<()> {
         self.state.contract_state.write().entry(address).or_default().nonce = nonce;
+        self.state_dirty.store(true, Ordering::SeqCst);
         Ok(())
     }
 }
+
+fn count_set_slots(slots: &HashMap<StorageKey, StorageValue>) -> usize {
+    slots.values().filter(|value| **value != FieldElement::ZERO).count()
+}
+
+/// Returns the number of the first block in `range` whose parent hash doesn't match the hash of
+/// the block before it, or `None` if all the blocks link back to each other.
+fn first_broken_link(
+    storage: &CacheDb<()>,
+    range: RangeInclusive<u64>,
+) -> Result<Option<BlockNumber>> {
+    let mut parent_hash = None;
+    for num in range {
+        let (Some(header), Some(hash)) =
+            (storage.block_headers.get(&num), storage.block_hashes.get(&num))
+        else {
+            return Err(InMemoryProviderError::MissingBlock(num).into());
+        };
+
+        if parent_hash.map_or(false, |parent_hash| header.parent_hash != parent_hash) {
+            return Ok(Some(num));
+        }
+
+        parent_hash = Some(*hash);
+    }
+
+    Ok(None)
+}
+
+/// Returns the state root of the parent of the given block, or zero for the genesis block.
+fn parent_state_root(storage: &CacheDb<()>, block_number: BlockNumber) -> Result<FieldElement> {
+    match block_number.checked_sub(1) {
+        Some(parent) => match storage.block_headers.get(&parent) {
+            Some(header) => Ok(header.state_root),
+            None => Err(InMemoryProviderError::MissingBlock(parent).into()),
+        },
+        None => Ok(FieldElement::ZERO),
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use katana_primitives::block::{BlockHash, BlockNumber, Header};
+use parking_lot::RwLock;
+
+use super::cache::CacheDb;
+
+/// A sensible default number of shards for [`LockStrategy::Sharded`].
+pub const DEFAULT_SHARD_COUNT: usize = 16;
+
+/// Determines how the block lookups of the provider are synchronized with the writers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LockStrategy {
+    /// Every lookup goes through the storage lock, so lookups wait for the block insertions.
+    #[default]
+    Single,
+    /// The headers, hashes and numbers of the blocks are also kept in maps split into `shards`
+    /// independently locked shards, so that the lookups of the
+    /// [`HeaderProvider`](crate::traits::block::HeaderProvider),
+    /// [`BlockHashProvider`](crate::traits::block::BlockHashProvider) and
+    /// [`BlockNumberProvider`](crate::traits::block::BlockNumberProvider) impls only contend with
+    /// the writes to the same shard. Every other lookup still goes through the storage lock.
+    Sharded { shards: usize },
+}
+
+/// A map split into independently locked shards, the shard of an entry being picked by the hash
+/// of its key.
+#[derive(Debug)]
+pub struct ShardedMap<K, V> {
+    hasher: RandomState,
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedMap<K, V> {
+    /// Creates an empty map with the given number of shards, at least one.
+    pub fn new(shards: usize) -> Self {
+        let shards = (0..shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect();
+        Self { hasher: RandomState::new(), shards }
+    }
+
+    /// Returns a copy of the value of `key`, locking only its shard.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).read().get(key).cloned()
+    }
+
+    /// Inserts a value, locking only the shard of `key`.
+    pub fn insert(&self, key: K, value: V) {
+        self.shard(&key).write().insert(key, value);
+    }
+
+    /// Removes the value of `key`, locking only its shard.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).write().remove(key)
+    }
+
+    /// Removes all the entries, one shard at a time.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.write().clear();
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+/// The headers, hashes and numbers of the blocks, kept alongside the storage when the provider
+/// uses [`LockStrategy::Sharded`].
+///
+/// The index is only written to while the storage lock is held, and only ever holds blocks that
+/// are fully written to the storage: a block is added once all of its data has been inserted, and
+/// removed before any of its data is.
+#[derive(Debug)]
+pub(super) struct ShardedBlockIndex {
+    pub(super) hashes: ShardedMap<BlockNumber, BlockHash>,
+    pub(super) numbers: ShardedMap<BlockHash, BlockNumber>,
+    pub(super) headers: ShardedMap<BlockNumber, Header>,
+}
+
+impl ShardedBlockIndex {
+    pub(super) fn new(shards: usize) -> Self {
+        Self {
+            hashes: ShardedMap::new(shards),
+            numbers: ShardedMap::new(shards),
+            headers: ShardedMap::new(shards),
+        }
+    }
+
+    pub(super) fn insert(&self, number: BlockNumber, hash: BlockHash, header: Header) {
+        self.hashes.insert(number, hash);
+        self.numbers.insert(hash, number);
+        self.headers.insert(number, header);
+    }
+
+    pub(super) fn remove(&self, number: BlockNumber, hash: BlockHash) {
+        self.hashes.remove(&number);
+        self.numbers.remove(&hash);
+        self.headers.remove(&number);
+    }
+
+    pub(super) fn clear(&self) {
+        self.hashes.clear();
+        self.numbers.clear();
+        self.headers.clear();
+    }
+
+    /// Adds all the blocks of `storage` to the index.
+    pub(super) fn fill(&self, storage: &CacheDb<()>) {
+        for (number, hash) in &storage.block_hashes {
+            let header = storage.block_headers.get(number).expect("header should exist");
+            self.insert(*number, *hash, header.clone());
+        }
+    }
+}
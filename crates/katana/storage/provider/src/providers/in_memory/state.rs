@@ -1,4 +1,4 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 
 use katana_primitives::block::BlockNumber;
@@ -6,12 +6,14 @@ use katana_primitives::contract::{
     ClassHash, CompiledClassHash, CompiledContractClass, ContractAddress, GenericContractInfo,
     Nonce, SierraClass, StorageKey, StorageValue,
 };
+use katana_primitives::state::StateUpdatesWithDeclaredClasses;
 
 use super::cache::{CacheSnapshotWithoutClasses, CacheStateDb, SharedContractClasses};
 use crate::traits::contract::{ContractClassProvider, ContractInfoProvider};
 use crate::traits::state::StateProvider;
 use crate::Result;
 
+#[derive(Clone)]
 pub struct StateSnapshot<Db> {
     // because the classes are shared between snapshots, when trying to fetch check the compiled
     // hash first and then the sierra class to ensure the class should be present in the snapshot.
@@ -22,35 +24,62 @@ pub struct StateSnapshot<Db> {
 const DEFAULT_HISTORY_LIMIT: usize = 500;
 const MIN_HISTORY_LIMIT: usize = 10;
 
+/// The number of blocks between two full snapshots when using [`SnapshotStrategy::Diff`].
+pub(super) const DIFF_SNAPSHOT_INTERVAL: u64 = 64;
+
+/// Determines how the historical state of each block is stored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotStrategy {
+    /// Stores a full snapshot of the state for every block.
+    #[default]
+    Full,
+    /// Stores a full snapshot only every [`DIFF_SNAPSHOT_INTERVAL`] blocks. The state of the
+    /// blocks in between is reconstructed on demand by replaying the blocks' state updates on
+    /// top of the nearest full snapshot.
+    ///
+    /// This trades read performance for memory. Since the changes made directly through
+    /// [`StateWriter`](crate::traits::state::StateWriter) are not part of any block's state
+    /// updates, the next block inserted after such a change always gets a full snapshot.
+    Diff,
+}
+
 /// Represents the complete state of a single block.
 ///
 /// It should store at N - 1 states, where N is the latest block number.
-pub struct HistoricalStates {
+pub struct HistoricalStates<S: ?Sized = dyn StateProvider> {
     /// The states at a certain block based on the block number
-    states: HashMap<BlockNumber, Arc<dyn StateProvider>>,
+    states: BTreeMap<BlockNumber, Arc<S>>,
     /// How many states to store at most
     in_memory_limit: usize,
     /// minimum amount of states we keep in memory
     min_in_memory_limit: usize,
     /// all states present, used to enforce `in_memory_limit`
     present: VecDeque<BlockNumber>,
+    /// The block number of the most recently evicted state, if any
+    last_evicted: Option<BlockNumber>,
 }
 
-impl HistoricalStates {
+impl<S: ?Sized> HistoricalStates<S> {
     pub fn new(limit: usize) -> Self {
         Self {
             in_memory_limit: limit,
             states: Default::default(),
             present: Default::default(),
+            last_evicted: None,
             min_in_memory_limit: limit.min(MIN_HISTORY_LIMIT),
         }
     }
 
     /// Returns the state for the given `block_hash` if present
-    pub fn get(&self, block_num: &BlockNumber) -> Option<&Arc<dyn StateProvider>> {
+    pub fn get(&self, block_num: &BlockNumber) -> Option<&Arc<S>> {
         self.states.get(block_num)
     }
 
+    /// Returns the state with the highest block number that is lower than or equal to `block_num`.
+    pub fn get_at_or_below(&self, block_num: BlockNumber) -> Option<(BlockNumber, &Arc<S>)> {
+        self.states.range(..=block_num).next_back().map(|(num, state)| (*num, state))
+    }
+
     /// Inserts a new (block_hash -> state) pair
     ///
     /// When the configured limit for the number of states that can be stored in memory is reached,
@@ -59,31 +88,83 @@ impl HistoricalStates {
     /// Since we keep a snapshot of the entire state as history, the size of the state will increase
     /// with the transactions processed. To counter this, we gradually decrease the cache limit with
     /// the number of states/blocks until we reached the `min_limit`.
-    pub fn insert(&mut self, block_num: BlockNumber, state: Box<dyn StateProvider>) {
+    ///
+    /// Returns the states that were evicted to make room for the new state, oldest first.
+    pub fn insert(&mut self, block_num: BlockNumber, state: Box<S>) -> Vec<(BlockNumber, Arc<S>)> {
+        self.insert_shared(block_num, Arc::from(state))
+    }
+
+    /// Inserts a state that may be shared with other blocks, see [`Self::insert`].
+    pub fn insert_shared(
+        &mut self,
+        block_num: BlockNumber,
+        state: Arc<S>,
+    ) -> Vec<(BlockNumber, Arc<S>)> {
         if self.present.len() >= self.in_memory_limit {
             // once we hit the max limit we gradually decrease it
             self.in_memory_limit =
                 self.in_memory_limit.saturating_sub(1).max(self.min_in_memory_limit);
         }
 
-        self.enforce_limits();
-        self.states.insert(block_num, Arc::new(state));
+        let evicted = self.enforce_limits();
+        self.states.insert(block_num, state);
         self.present.push_back(block_num);
+        evicted
+    }
+
+    /// Returns the number of states stored.
+    pub fn len(&self) -> usize {
+        self.present.len()
+    }
+
+    /// Returns `true` if no states are stored.
+    pub fn is_empty(&self) -> bool {
+        self.present.is_empty()
+    }
+
+    /// Returns the stored states, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = (BlockNumber, &Arc<S>)> {
+        self.present.iter().filter_map(|num| self.states.get(num).map(|state| (*num, state)))
     }
 
-    /// Enforces configured limits
-    fn enforce_limits(&mut self) {
+    /// Removes the oldest state and returns it.
+    pub fn evict_oldest(&mut self) -> Option<(BlockNumber, Arc<S>)> {
+        let block_num = self.present.pop_front()?;
+        self.last_evicted = Some(block_num);
+        self.states.remove(&block_num).map(|state| (block_num, state))
+    }
+
+    /// Returns the block number below which the states have been evicted, or `None` if no state
+    /// has been evicted yet.
+    pub fn pruned_floor(&self) -> Option<BlockNumber> {
+        let last_evicted = self.last_evicted?;
+        Some(self.present.front().copied().unwrap_or(last_evicted + 1))
+    }
+
+    /// Removes the states of all the blocks above `block_num`.
+    pub fn remove_above(&mut self, block_num: BlockNumber) {
+        self.present.retain(|num| *num <= block_num);
+        self.states.retain(|num, _| *num <= block_num);
+    }
+
+    /// Enforces configured limits, returning the evicted states.
+    fn enforce_limits(&mut self) -> Vec<(BlockNumber, Arc<S>)> {
+        let mut evicted = Vec::new();
         // enforce memory limits
         while self.present.len() >= self.in_memory_limit {
             // evict the oldest block in memory
             if let Some(block_num) = self.present.pop_front() {
-                self.states.remove(&block_num);
+                self.last_evicted = Some(block_num);
+                if let Some(state) = self.states.remove(&block_num) {
+                    evicted.push((block_num, state));
+                }
             }
         }
+        evicted
     }
 }
 
-impl Default for HistoricalStates {
+impl<S: ?Sized> Default for HistoricalStates<S> {
     fn default() -> Self {
         // enough in memory to store `DEFAULT_HISTORY_LIMIT` blocks in memory
         Self::new(DEFAULT_HISTORY_LIMIT)
@@ -221,8 +302,70 @@ impl ContractClassProvider for LatestStateProvider {
     }
 }
 
+/// A state provider overlaying the pending state updates on top of the latest state.
+pub(super) struct PendingStateProvider {
+    pub(super) latest: LatestStateProvider,
+    pub(super) pending: Arc<StateUpdatesWithDeclaredClasses>,
+}
+
+impl StateProvider for PendingStateProvider {
+    fn nonce(&self, address: ContractAddress) -> Result<Option<Nonce>> {
+        match self.pending.state_updates.nonce_updates.get(&address) {
+            Some(nonce) => Ok(Some(*nonce)),
+            None => self.latest.nonce(address),
+        }
+    }
+
+    fn storage(
+        &self,
+        address: ContractAddress,
+        storage_key: StorageKey,
+    ) -> Result<Option<StorageValue>> {
+        let storage_updates = &self.pending.state_updates.storage_updates;
+        match storage_updates.get(&address).and_then(|s| s.get(&storage_key)) {
+            Some(value) => Ok(Some(*value)),
+            None => self.latest.storage(address, storage_key),
+        }
+    }
+
+    fn class_hash_of_contract(&self, address: ContractAddress) -> Result<Option<ClassHash>> {
+        match self.pending.state_updates.contract_updates.get(&address) {
+            Some(class_hash) => Ok(Some(*class_hash)),
+            None => self.latest.class_hash_of_contract(address),
+        }
+    }
+}
+
+impl ContractClassProvider for PendingStateProvider {
+    fn sierra_class(&self, hash: ClassHash) -> Result<Option<SierraClass>> {
+        match self.pending.declared_sierra_classes.get(&hash) {
+            Some(class) => Ok(Some(class.clone())),
+            None => self.latest.sierra_class(hash),
+        }
+    }
+
+    fn class(&self, hash: ClassHash) -> Result<Option<CompiledContractClass>> {
+        match self.pending.declared_compiled_classes.get(&hash) {
+            Some(class) => Ok(Some(class.clone())),
+            None => self.latest.class(hash),
+        }
+    }
+
+    fn compiled_class_hash_of_class_hash(
+        &self,
+        hash: ClassHash,
+    ) -> Result<Option<CompiledClassHash>> {
+        match self.pending.state_updates.declared_classes.get(&hash) {
+            Some(compiled_hash) => Ok(Some(*compiled_hash)),
+            None => self.latest.compiled_class_hash_of_class_hash(hash),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use katana_primitives::block::BlockHashOrNumber;
     use katana_primitives::contract::{GenericContractInfo, StorageKey};
     use starknet::macros::felt;
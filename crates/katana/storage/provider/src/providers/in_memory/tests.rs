@@ -0,0 +1,2161 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use katana_core::constants::ERC20_CONTRACT;
+use katana_primitives::block::{
+    Block, BlockHash, BlockHashOrNumber, BlockNumber, FinalityStatus, Header, SealedBlockWithStatus,
+};
+use katana_primitives::contract::ContractAddress;
+use katana_primitives::receipt::{DeployAccountTxReceipt, Event, InvokeTxReceipt, Receipt};
+use katana_primitives::state::{StateUpdates, StateUpdatesWithDeclaredClasses};
+use katana_primitives::transaction::{
+    DeployAccountTx, InvokeTx, L1HandlerTx, Tx, TxHash, TxWithHash,
+};
+use katana_primitives::utils::class::parse_compiled_class;
+use katana_primitives::FieldElement;
+use parking_lot::Mutex;
+use starknet::macros::{felt, selector};
+
+use super::error::InMemoryProviderError;
+use super::event::{EmittedEvent, EventPosition};
+use super::sharded::LockStrategy;
+use super::state::{SnapshotStrategy, DIFF_SNAPSHOT_INTERVAL};
+use super::{AccountSnapshot, ExecutionStatus, InMemoryProvider, PersistRecord, UDC_ADDRESS};
+use crate::traits::block::{
+    BlockHashProvider, BlockNumberProvider, BlockProvider, BlockWriter, HeaderProvider,
+};
+use crate::traits::contract::{ContractClassProvider, ContractClassWriter};
+use crate::traits::state::{StateFactoryProvider, StateProvider, StateRootProvider, StateWriter};
+use crate::traits::state_update::StateUpdateProvider;
+use crate::traits::transaction::{ReceiptProvider, TransactionProvider};
+
+fn block_hash(num: BlockNumber) -> BlockHash {
+    FieldElement::from(0xb10c_0000u64 + num)
+}
+
+fn tx_hash(block_num: BlockNumber, idx: usize) -> TxHash {
+    FieldElement::from((block_num << 32) + idx as u64 + 1)
+}
+
+fn invoke_receipt(actual_fee: u128) -> Receipt {
+    Receipt::Invoke(InvokeTxReceipt { actual_fee, ..Default::default() })
+}
+
+/// Returns the header of the block at `num`, pointing to the block below it.
+fn header(num: BlockNumber) -> Header {
+    let parent_hash = if num == 0 { FieldElement::ZERO } else { block_hash(num - 1) };
+    Header { parent_hash, number: num, ..Default::default() }
+}
+
+/// Returns `count` dummy invoke transactions of the block at `num`.
+fn invoke_txs(num: BlockNumber, count: usize) -> Vec<TxWithHash> {
+    (0..count)
+        .map(|idx| TxWithHash {
+            hash: tx_hash(num, idx),
+            transaction: Tx::Invoke(Default::default()),
+        })
+        .collect()
+}
+
+/// Seals a block accepted on L2 with the hash of its number.
+fn sealed_block(header: Header, body: Vec<TxWithHash>) -> SealedBlockWithStatus {
+    let hash = block_hash(header.number);
+    let block = Block { header, body }.seal_with_hash(hash);
+    SealedBlockWithStatus { block, status: FinalityStatus::AcceptedOnL2 }
+}
+
+/// Inserts a block at `num` with one dummy invoke transaction for each of the given receipts.
+fn insert_block(
+    provider: &InMemoryProvider,
+    num: BlockNumber,
+    receipts: Vec<Receipt>,
+    states: StateUpdatesWithDeclaredClasses,
+) {
+    let block = sealed_block(header(num), invoke_txs(num, receipts.len()));
+    provider.insert_block_with_states_and_receipts(block, states, receipts).unwrap();
+}
+
+#[test]
+fn total_fees_in_range() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(10), invoke_receipt(20)], Default::default());
+    insert_block(&provider, 1, vec![invoke_receipt(5)], Default::default());
+    insert_block(&provider, 2, vec![], Default::default());
+
+    assert_eq!(provider.total_fees_in_range(0..=2).unwrap(), FieldElement::from(35u8));
+    assert_eq!(provider.total_fees_in_range(1..=2).unwrap(), FieldElement::from(5u8));
+
+    let err = provider.total_fees_in_range(1..=3).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::MissingBlock(3))
+    ));
+}
+
+#[test]
+fn contract_storage_at() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let provider = InMemoryProvider::new();
+    for (num, slots) in [
+        (0, vec![(felt!("0x1"), felt!("0x10"))]),
+        (1, vec![(felt!("0x1"), felt!("0x11")), (felt!("0x2"), felt!("0x20"))]),
+        (2, vec![(felt!("0x3"), felt!("0x30"))]),
+    ] {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states.state_updates.storage_updates.insert(address, HashMap::from_iter(slots));
+        insert_block(&provider, num, vec![], states);
+    }
+
+    let expected = [
+        HashMap::from([(felt!("0x1"), felt!("0x10"))]),
+        HashMap::from([(felt!("0x1"), felt!("0x11")), (felt!("0x2"), felt!("0x20"))]),
+        HashMap::from([
+            (felt!("0x1"), felt!("0x11")),
+            (felt!("0x2"), felt!("0x20")),
+            (felt!("0x3"), felt!("0x30")),
+        ]),
+    ];
+
+    for (num, expected) in expected.into_iter().enumerate() {
+        let actual = provider.contract_storage_at(address, num as BlockNumber).unwrap();
+        assert_eq!(actual, Some(expected));
+    }
+
+    let unknown = ContractAddress::from(felt!("0xdead"));
+    assert_eq!(provider.contract_storage_at(unknown, 2).unwrap(), Some(HashMap::new()));
+    assert_eq!(provider.contract_storage_at(address, 3).unwrap(), None);
+}
+
+#[tokio::test]
+async fn wait_for_block() {
+    let provider = Arc::new(InMemoryProvider::new());
+    insert_block(&provider, 0, vec![], Default::default());
+
+    // the tip is already at the requested block
+    assert_eq!(provider.wait_for_block(0).await.unwrap(), 0);
+
+    let waiter = tokio::spawn({
+        let provider = Arc::clone(&provider);
+        async move { provider.wait_for_block(2).await.unwrap() }
+    });
+
+    tokio::task::yield_now().await;
+    insert_block(&provider, 1, vec![], Default::default());
+    insert_block(&provider, 2, vec![], Default::default());
+
+    let tip = tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+    assert_eq!(tip, 2);
+}
+
+#[test]
+fn remove_last_transaction() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(1), invoke_receipt(2)], Default::default());
+    insert_block(&provider, 1, vec![invoke_receipt(3), invoke_receipt(4)], Default::default());
+    insert_block(&provider, 2, vec![], Default::default());
+
+    let removed = provider.remove_last_transaction().unwrap().unwrap();
+    assert_eq!(removed.hash, tx_hash(1, 1));
+
+    // the indices of the affected block must stay consistent with the remaining data
+    assert_eq!(provider.transaction_by_hash(removed.hash).unwrap(), None);
+    assert_eq!(provider.receipt_by_hash(removed.hash).unwrap(), None);
+    assert_eq!(provider.transaction_count_by_block(BlockHashOrNumber::Num(1)).unwrap(), Some(1));
+    assert_eq!(
+        provider.receipts_by_block(BlockHashOrNumber::Num(1)).unwrap(),
+        Some(vec![invoke_receipt(3)])
+    );
+
+    let txs = provider.transactions_by_block(BlockHashOrNumber::Num(1)).unwrap().unwrap();
+    assert_eq!(txs.iter().map(|tx| tx.hash).collect::<Vec<_>>(), vec![tx_hash(1, 0)]);
+    assert_eq!(
+        provider.transaction_block_num_and_hash(tx_hash(1, 0)).unwrap(),
+        Some((1, block_hash(1)))
+    );
+
+    for expected in [tx_hash(1, 0), tx_hash(0, 1), tx_hash(0, 0)] {
+        assert_eq!(provider.remove_last_transaction().unwrap().map(|tx| tx.hash), Some(expected));
+    }
+
+    assert_eq!(provider.remove_last_transaction().unwrap(), None);
+    assert_eq!(provider.transaction_count_by_block(BlockHashOrNumber::Num(0)).unwrap(), Some(0));
+    assert_eq!(provider.receipts_by_block(BlockHashOrNumber::Num(0)).unwrap(), Some(vec![]));
+}
+
+#[test]
+fn receipts_with_event_key() {
+    let key = felt!("0x6b6579");
+    let other_key = felt!("0x6f74686572");
+
+    let receipt_with_keys = |keys: Vec<Vec<FieldElement>>| {
+        let events = keys
+            .into_iter()
+            .map(|keys| Event { from_address: ContractAddress::default(), keys, data: vec![] })
+            .collect();
+        Receipt::Invoke(InvokeTxReceipt { events, ..Default::default() })
+    };
+
+    let provider = InMemoryProvider::new();
+    insert_block(
+        &provider,
+        0,
+        vec![receipt_with_keys(vec![vec![key]]), receipt_with_keys(vec![vec![other_key]])],
+        Default::default(),
+    );
+    insert_block(
+        &provider,
+        1,
+        vec![
+            // the key is only matched against the first key of an event
+            receipt_with_keys(vec![vec![other_key, key]]),
+            receipt_with_keys(vec![vec![other_key], vec![key, other_key]]),
+        ],
+        Default::default(),
+    );
+
+    let actual = provider.receipts_with_event_key(key, 0..=1).unwrap();
+    let hashes = actual.iter().map(|(hash, _)| *hash).collect::<Vec<_>>();
+    assert_eq!(hashes, vec![tx_hash(0, 0), tx_hash(1, 1)]);
+    assert_eq!(actual[1].1, receipt_with_keys(vec![vec![other_key], vec![key, other_key]]));
+
+    assert_eq!(provider.receipts_with_event_key(key, 1..=5).unwrap().len(), 1);
+    assert!(provider.receipts_with_event_key(felt!("0x404"), 0..=1).unwrap().is_empty());
+}
+
+#[test]
+fn historical_reads_are_identical_across_snapshot_strategies() {
+    let full = InMemoryProvider::new().with_snapshot_strategy(SnapshotStrategy::Full);
+    let diff = InMemoryProvider::new().with_snapshot_strategy(SnapshotStrategy::Diff);
+
+    let addresses = [ContractAddress::from(felt!("0x1")), ContractAddress::from(felt!("0x2"))];
+
+    // enough blocks to span multiple full snapshots of the diff strategy
+    let block_count = DIFF_SNAPSHOT_INTERVAL * 2 + 10;
+
+    for num in 0..block_count {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        // only touch some of the blocks, and the contracts at different rates
+        if num % 3 == 0 {
+            let address = addresses[(num % 2) as usize];
+            let value = FieldElement::from(num);
+            let updates = &mut states.state_updates;
+            updates.nonce_updates.insert(address, value);
+            updates.contract_updates.insert(address, value);
+            updates.storage_updates.insert(address, HashMap::from([(felt!("0x1"), value)]));
+        }
+
+        // a direct write in between two full snapshots of the diff strategy
+        if num == DIFF_SNAPSHOT_INTERVAL + 10 {
+            let value = FieldElement::from(num);
+            full.set_storage(addresses[0], felt!("0x2"), value).unwrap();
+            diff.set_storage(addresses[0], felt!("0x2"), value).unwrap();
+        }
+
+        insert_block(&full, num, vec![], states.clone());
+        insert_block(&diff, num, vec![], states);
+    }
+
+    for num in 0..block_count {
+        let full_state = full.historical(BlockHashOrNumber::Num(num)).unwrap().unwrap();
+        let diff_state = diff.historical(BlockHashOrNumber::Num(num)).unwrap().unwrap();
+
+        assert_eq!(
+            full_state.storage(addresses[0], felt!("0x2")).unwrap(),
+            diff_state.storage(addresses[0], felt!("0x2")).unwrap()
+        );
+        for address in addresses {
+            assert_eq!(full_state.nonce(address).unwrap(), diff_state.nonce(address).unwrap());
+            assert_eq!(
+                full_state.class_hash_of_contract(address).unwrap(),
+                diff_state.class_hash_of_contract(address).unwrap()
+            );
+            assert_eq!(
+                full_state.storage(address, felt!("0x1")).unwrap(),
+                diff_state.storage(address, felt!("0x1")).unwrap()
+            );
+        }
+    }
+
+    assert!(full.historical(BlockHashOrNumber::Num(block_count)).unwrap().is_none());
+    assert!(diff.historical(BlockHashOrNumber::Num(block_count)).unwrap().is_none());
+}
+
+#[test]
+fn headers() {
+    let provider = InMemoryProvider::new();
+    for num in 0..3 {
+        insert_block(&provider, num, vec![], Default::default());
+    }
+
+    let ids = [
+        BlockHashOrNumber::Num(2),
+        BlockHashOrNumber::Hash(block_hash(0)),
+        BlockHashOrNumber::Num(10),
+        BlockHashOrNumber::Hash(felt!("0xdead")),
+        BlockHashOrNumber::Hash(block_hash(1)),
+    ];
+
+    let numbers = provider
+        .headers(&ids)
+        .unwrap()
+        .into_iter()
+        .map(|header| header.map(|h| h.number))
+        .collect::<Vec<_>>();
+
+    assert_eq!(numbers, vec![Some(2), Some(0), None, None, Some(1)]);
+}
+
+#[test]
+fn indexed_event_queries_match_scan() {
+    let addresses = [ContractAddress::from(felt!("0xa")), ContractAddress::from(felt!("0xb"))];
+    let keys = [felt!("0x1"), felt!("0x2"), felt!("0x3")];
+
+    let indexed = InMemoryProvider::new().with_event_index(true);
+    let scanned = InMemoryProvider::new();
+    let late_indexed = InMemoryProvider::new();
+
+    for num in 0..6u64 {
+        let receipts = (0..num as usize % 3 + 1)
+            .map(|i| {
+                let events = (0..i + 1)
+                    .map(|j| Event {
+                        from_address: addresses[(num as usize + j) % 2],
+                        keys: vec![keys[(i + j) % 3]],
+                        data: vec![],
+                    })
+                    .collect();
+                Receipt::Invoke(InvokeTxReceipt { events, ..Default::default() })
+            })
+            .collect::<Vec<_>>();
+
+        insert_block(&indexed, num, receipts.clone(), Default::default());
+        insert_block(&scanned, num, receipts.clone(), Default::default());
+        insert_block(&late_indexed, num, receipts, Default::default());
+    }
+
+    let late_indexed = late_indexed.with_event_index(true);
+
+    let address_filters = [None, Some(addresses[0]), Some(addresses[1])];
+    let key_filters = [None, Some(keys[0]), Some(keys[2]), Some(felt!("0x404"))];
+
+    for address in address_filters {
+        for key in key_filters {
+            for range in [0..=5, 1..=3, 4..=10] {
+                let expected = scanned.filter_events(address, key, range.clone()).unwrap();
+                let actual = indexed.filter_events(address, key, range.clone()).unwrap();
+                let late = late_indexed.filter_events(address, key, range.clone()).unwrap();
+
+                assert_eq!(actual, expected, "address {address:?} key {key:?} range {range:?}");
+                assert_eq!(late, expected, "address {address:?} key {key:?} range {range:?}");
+            }
+        }
+    }
+
+    let all = scanned.filter_events(None, None, 0..=5).unwrap();
+    assert_eq!(all.len(), (0..6).map(|n| [1, 3, 6][n % 3]).sum::<usize>());
+}
+
+#[test]
+fn cumulative_tx_count() {
+    let provider = InMemoryProvider::new();
+    for (num, tx_count) in [(0, 2), (1, 0), (2, 3), (3, 1)] {
+        insert_block(&provider, num, vec![invoke_receipt(0); tx_count], Default::default());
+    }
+
+    let counts =
+        (0..4).map(|num| provider.cumulative_tx_count(num).unwrap().unwrap()).collect::<Vec<_>>();
+    assert_eq!(counts, vec![2, 2, 5, 6]);
+    assert!(counts.windows(2).all(|w| w[0] <= w[1]));
+
+    assert_eq!(provider.cumulative_tx_count(4).unwrap(), None);
+}
+
+#[test]
+fn on_evict_snapshot_callback() {
+    let provider = InMemoryProvider::new();
+
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    provider.set_on_evict_snapshot(Box::new({
+        let evicted = Arc::clone(&evicted);
+        move |num: BlockNumber, state: &dyn StateProvider| {
+            // the snapshot must still be readable from within the callback
+            assert!(state.nonce(ContractAddress::default()).is_ok());
+            evicted.lock().push(num);
+        }
+    }));
+
+    // insert blocks until the history limit is reached
+    let mut num = 0;
+    while evicted.lock().is_empty() {
+        insert_block(&provider, num, vec![], Default::default());
+        num += 1;
+    }
+
+    let evicted = evicted.lock().clone();
+    assert_eq!(evicted[0], 0);
+    for num in evicted {
+        assert!(provider.historical(BlockHashOrNumber::Num(num)).unwrap().is_none());
+    }
+    assert!(provider.historical(BlockHashOrNumber::Num(num - 1)).unwrap().is_some());
+}
+
+#[test]
+fn latest_transaction() {
+    let provider = InMemoryProvider::new();
+    assert_eq!(provider.latest_transaction().unwrap(), None);
+
+    insert_block(&provider, 0, vec![], Default::default());
+    assert_eq!(provider.latest_transaction().unwrap(), None);
+
+    insert_block(&provider, 1, vec![invoke_receipt(0); 3], Default::default());
+    insert_block(&provider, 2, vec![], Default::default());
+
+    let tx = provider.latest_transaction().unwrap().unwrap();
+    assert_eq!(tx.hash, tx_hash(1, 2));
+    assert_eq!(Some(tx.clone()), provider.transaction_by_hash(tx.hash).unwrap());
+}
+
+#[test]
+fn export_and_import_block_range() {
+    let address = ContractAddress::from(felt!("0x1337"));
+    let class_hash = felt!("0xc1a55");
+
+    let blocks = (0..=5u64).map(|num| {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states.state_updates.storage_updates.insert(
+            address,
+            HashMap::from([(FieldElement::from(num), FieldElement::from(num + 100))]),
+        );
+        if num == 4 {
+            states.state_updates.declared_classes.insert(class_hash, felt!("0xcaca0"));
+            states.declared_compiled_classes.insert(class_hash, (*ERC20_CONTRACT).clone());
+        }
+        (num, vec![invoke_receipt(num as u128)], states)
+    });
+
+    let source = InMemoryProvider::new();
+    let destination = InMemoryProvider::new();
+    for (num, receipts, states) in blocks {
+        insert_block(&source, num, receipts.clone(), states.clone());
+        if num <= 2 {
+            insert_block(&destination, num, receipts, states);
+        }
+    }
+
+    let bytes = source.export_block_range(3..=5).unwrap();
+    destination.import_block_range(&bytes).unwrap();
+
+    assert_eq!(destination.latest_number().unwrap(), 5);
+    assert_eq!(destination.latest_hash().unwrap(), block_hash(5));
+    for num in 3..=5 {
+        let id = BlockHashOrNumber::Num(num);
+        assert_eq!(destination.block(id).unwrap(), source.block(id).unwrap());
+        assert_eq!(
+            destination.receipts_by_block(id).unwrap(),
+            source.receipts_by_block(id).unwrap()
+        );
+    }
+
+    let state = destination.latest().unwrap();
+    assert_eq!(state.storage(address, felt!("0x5")).unwrap(), Some(felt!("0x69")));
+    assert_eq!(state.class(class_hash).unwrap(), Some((*ERC20_CONTRACT).clone()));
+    assert_eq!(
+        state.compiled_class_hash_of_class_hash(class_hash).unwrap(),
+        Some(felt!("0xcaca0"))
+    );
+
+    // the exported range no longer follows the tip of the destination
+    let err = destination.import_block_range(&bytes).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::NonContiguousBlock { expected: 6, actual: 3 })
+    ));
+
+    // the exported range doesn't extend the chain of the destination
+    let forked = InMemoryProvider::new();
+    insert_block(&forked, 0, vec![], Default::default());
+    insert_block(&forked, 1, vec![], Default::default());
+    let mut block = sealed_block(header(2), vec![]);
+    block.block.header.hash = felt!("0xf0");
+    forked.insert_block_with_states_and_receipts(block, Default::default(), vec![]).unwrap();
+
+    let err = forked.import_block_range(&bytes).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::ParentHashMismatch { number: 3, .. })
+    ));
+    assert_eq!(forked.latest_number().unwrap(), 2);
+}
+
+#[test]
+fn import_block_range_with_invalid_state_root() {
+    // a stand-in for the state root computation, counting the storage updates of every block
+    let compute_state_root = |parent_root: FieldElement, updates: &StateUpdates| {
+        parent_root + FieldElement::from(updates.storage_updates.len() as u64)
+    };
+
+    let address = ContractAddress::from(felt!("0x1337"));
+    let source = InMemoryProvider::new();
+    for (num, state_root) in [(0, felt!("0x1")), (1, felt!("0x2")), (2, felt!("0xbad"))] {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states
+            .state_updates
+            .storage_updates
+            .insert(address, HashMap::from([(FieldElement::from(num), felt!("0x1"))]));
+
+        let block = sealed_block(header(num), vec![]);
+        source.insert_block_with_state_root(block, states, vec![], state_root).unwrap();
+    }
+    insert_block(&source, 3, vec![], Default::default());
+
+    // block 2 doesn't commit to the state root computed on top of block 1
+    let destination =
+        InMemoryProvider::new().with_state_root_validation(Box::new(compute_state_root));
+    let bytes = source.export_block_range(0..=3).unwrap();
+    let err = destination.import_block_range(&bytes).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::StateRootMismatch { number: 2, expected, actual })
+            if *expected == felt!("0x3") && *actual == felt!("0xbad")
+    ));
+
+    // none of the blocks before the invalid one is imported
+    assert_eq!(destination.block_hash_by_num(0).unwrap(), None);
+    assert_eq!(destination.block_hash_by_num(1).unwrap(), None);
+    let latest = destination.latest().unwrap();
+    assert_eq!(latest.storage(address, felt!("0x0")).unwrap(), None);
+
+    // the blocks up to the invalid one are imported once exported on their own
+    let bytes = source.export_block_range(0..=1).unwrap();
+    destination.import_block_range(&bytes).unwrap();
+    assert_eq!(destination.latest_number().unwrap(), 1);
+    assert_eq!(destination.state_root(BlockHashOrNumber::Num(1)).unwrap(), Some(felt!("0x2")));
+}
+
+#[test]
+fn replay_into() {
+    let source = InMemoryProvider::new();
+    for num in 0..=4 {
+        insert_block(&source, num, vec![invoke_receipt(num as u128)], Default::default());
+    }
+
+    let wal = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(wal.path(), source.export_block_range(0..=4).unwrap()).unwrap();
+
+    let provider = InMemoryProvider::new();
+    assert_eq!(provider.replay_into(wal.path(), Some(2)).unwrap(), 3);
+    assert_eq!(provider.latest_number().unwrap(), 2);
+    assert_eq!(provider.latest_hash().unwrap(), block_hash(2));
+
+    // the blocks already applied are skipped
+    assert_eq!(provider.replay_into(wal.path(), None).unwrap(), 2);
+    assert_eq!(provider.latest_number().unwrap(), 4);
+    for num in 0..=4 {
+        let id = BlockHashOrNumber::Num(num);
+        assert_eq!(provider.block(id).unwrap(), source.block(id).unwrap());
+        assert_eq!(provider.receipts_by_block(id).unwrap(), source.receipts_by_block(id).unwrap());
+    }
+
+    assert_eq!(provider.replay_into(wal.path(), None).unwrap(), 0);
+    assert_eq!(provider.latest_number().unwrap(), 4);
+}
+
+#[test]
+fn would_reorg() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![], Default::default());
+    insert_block(&provider, 1, vec![], Default::default());
+
+    assert!(provider.would_reorg(1, felt!("0xdead")).unwrap());
+    assert!(!provider.would_reorg(1, block_hash(1)).unwrap());
+    assert!(!provider.would_reorg(2, felt!("0xdead")).unwrap());
+}
+
+/// Asserts that the blocks up to `present` can be looked up, and that the blocks above it up to
+/// `absent` can't.
+fn assert_block_lookups(provider: &InMemoryProvider, present: BlockNumber, absent: BlockNumber) {
+    for num in 0..=present {
+        let header = provider.header(BlockHashOrNumber::Num(num)).unwrap().unwrap();
+        assert_eq!(header.number, num);
+        let by_hash = provider.header(BlockHashOrNumber::Hash(block_hash(num))).unwrap();
+        assert_eq!(by_hash, Some(header));
+        assert_eq!(provider.block_hash_by_num(num).unwrap(), Some(block_hash(num)));
+        assert_eq!(provider.block_number_by_hash(block_hash(num)).unwrap(), Some(num));
+    }
+    for num in present + 1..=absent {
+        assert_eq!(provider.header(BlockHashOrNumber::Num(num)).unwrap(), None);
+        assert_eq!(provider.header(BlockHashOrNumber::Hash(block_hash(num))).unwrap(), None);
+        assert_eq!(provider.block_hash_by_num(num).unwrap(), None);
+        assert_eq!(provider.block_number_by_hash(block_hash(num)).unwrap(), None);
+    }
+}
+
+#[test]
+fn sharded_lock_strategy() {
+    let provider = InMemoryProvider::new().with_lock_strategy(LockStrategy::Sharded { shards: 4 });
+    for num in 0..=3 {
+        insert_block(&provider, num, vec![invoke_receipt(0)], Default::default());
+    }
+    assert_block_lookups(&provider, 3, 4);
+
+    provider.revert_block_returning(2).unwrap();
+    assert_block_lookups(&provider, 2, 3);
+
+    let new = InMemoryProvider::new();
+    for num in 0..=1 {
+        insert_block(&new, num, vec![invoke_receipt(0)], Default::default());
+    }
+    provider.swap_state(new);
+    assert_block_lookups(&provider, 1, 3);
+
+    // blocks already stored are indexed when switching to the sharded strategy
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(0)], Default::default());
+    let provider = provider.with_lock_strategy(LockStrategy::Sharded { shards: 1 });
+    assert_block_lookups(&provider, 0, 1);
+}
+
+#[test]
+fn class_pair() {
+    let legacy_hash = felt!("0x1");
+    let sierra_hash = felt!("0x2");
+
+    let artifact = include_str!("../../../../db/benches/artifacts/dojo_world_240.json");
+    let compiled_class = parse_compiled_class(artifact).unwrap();
+    let sierra_class =
+        serde_json::from_str::<starknet::core::types::contract::SierraClass>(artifact)
+            .unwrap()
+            .flatten()
+            .unwrap();
+
+    let provider = InMemoryProvider::new();
+    provider.set_class(legacy_hash, (*ERC20_CONTRACT).clone()).unwrap();
+    provider.set_class(sierra_hash, compiled_class.clone()).unwrap();
+    provider.set_sierra_class(sierra_hash, sierra_class.clone()).unwrap();
+
+    assert_eq!(provider.class_pair(legacy_hash).unwrap(), (Some((*ERC20_CONTRACT).clone()), None));
+    assert_eq!(
+        provider.class_pair(sierra_hash).unwrap(),
+        (Some(compiled_class), Some(sierra_class))
+    );
+    assert_eq!(provider.class_pair(felt!("0x3")).unwrap(), (None, None));
+}
+
+#[test]
+fn storage_slot_counts() {
+    let provider = InMemoryProvider::new();
+    for (address, slots) in [("0x1", 2u64), ("0x2", 5), ("0x3", 1), ("0x4", 5)] {
+        let address = ContractAddress::from(FieldElement::from_hex_be(address).unwrap());
+        for slot in 0..slots {
+            provider.set_storage(address, FieldElement::from(slot), felt!("0x1")).unwrap();
+        }
+    }
+    // a slot set to zero is not counted
+    provider.set_storage(felt!("0x3").into(), felt!("0x100"), FieldElement::ZERO).unwrap();
+
+    assert_eq!(provider.storage_slot_count(felt!("0x1").into()).unwrap(), 2);
+    assert_eq!(provider.storage_slot_count(felt!("0x3").into()).unwrap(), 1);
+    assert_eq!(provider.storage_slot_count(felt!("0x5").into()).unwrap(), 0);
+
+    assert_eq!(
+        provider.top_contracts_by_storage(3).unwrap(),
+        vec![(felt!("0x2").into(), 5), (felt!("0x4").into(), 5), (felt!("0x1").into(), 2)]
+    );
+    assert_eq!(provider.top_contracts_by_storage(10).unwrap().len(), 4);
+}
+
+#[test]
+fn pending_state() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let provider = InMemoryProvider::new();
+    provider.set_storage(address, felt!("0x1"), felt!("0x10")).unwrap();
+    provider.set_nonce(address, felt!("0x1")).unwrap();
+
+    let mut pending = StateUpdatesWithDeclaredClasses::default();
+    pending
+        .state_updates
+        .storage_updates
+        .insert(address, HashMap::from([(felt!("0x2"), felt!("0x20"))]));
+    pending.state_updates.nonce_updates.insert(address, felt!("0x2"));
+    provider.set_pending_state(pending);
+
+    let state = provider.pending_state().unwrap();
+    assert_eq!(state.storage(address, felt!("0x1")).unwrap(), Some(felt!("0x10")));
+    assert_eq!(state.storage(address, felt!("0x2")).unwrap(), Some(felt!("0x20")));
+    assert_eq!(state.nonce(address).unwrap(), Some(felt!("0x2")));
+
+    let latest = provider.latest().unwrap();
+    assert_eq!(latest.storage(address, felt!("0x2")).unwrap(), None);
+    assert_eq!(latest.nonce(address).unwrap(), Some(felt!("0x1")));
+
+    // inserting a block discards the pending state
+    insert_block(&provider, 0, vec![], Default::default());
+    let state = provider.pending_state().unwrap();
+    assert_eq!(state.storage(address, felt!("0x2")).unwrap(), None);
+    assert_eq!(state.nonce(address).unwrap(), Some(felt!("0x1")));
+}
+
+#[test]
+fn verify_chain_links() {
+    let provider = InMemoryProvider::new();
+    for num in 0..=3 {
+        insert_block(&provider, num, vec![], Default::default());
+    }
+
+    assert_eq!(provider.verify_chain_links(0..=3).unwrap(), None);
+
+    // block 4 doesn't point to block 3
+    let block = sealed_block(Header { parent_hash: felt!("0xdead"), ..header(4) }, vec![]);
+    provider.insert_block_with_states_and_receipts(block, Default::default(), vec![]).unwrap();
+    insert_block(&provider, 5, vec![], Default::default());
+
+    assert_eq!(provider.verify_chain_links(0..=5).unwrap(), Some(4));
+    assert_eq!(provider.verify_chain_links(0..=3).unwrap(), None);
+    assert_eq!(provider.verify_chain_links(4..=5).unwrap(), None);
+
+    let err = provider.verify_chain_links(4..=6).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::MissingBlock(6))
+    ));
+}
+
+#[test]
+fn raw_transaction_by_number() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(0), invoke_receipt(0)], Default::default());
+
+    assert_eq!(
+        provider.raw_transaction_by_number(1).unwrap(),
+        Some(Tx::Invoke(Default::default()))
+    );
+    assert_eq!(provider.raw_transaction_by_number(2).unwrap(), None);
+}
+
+#[test]
+fn block_event_bloom() {
+    let address = ContractAddress::from(felt!("0xa"));
+    let key = felt!("0x1");
+
+    let provider = InMemoryProvider::new();
+    let event = Event { from_address: address, keys: vec![key, felt!("0x2")], data: vec![] };
+    let receipt = Receipt::Invoke(InvokeTxReceipt { events: vec![event], ..Default::default() });
+    insert_block(&provider, 0, vec![receipt], Default::default());
+    insert_block(&provider, 1, vec![invoke_receipt(0)], Default::default());
+
+    let bloom = provider.block_event_bloom(0).unwrap().unwrap();
+    assert!(bloom.may_contain(&address));
+    assert!(bloom.may_contain(&key));
+    assert!(bloom.may_contain(&felt!("0x2")));
+
+    // bloom filters may return false positives, but never for a block without events
+    let bloom = provider.block_event_bloom(1).unwrap().unwrap();
+    assert!(!bloom.may_contain(&address));
+    assert!(!bloom.may_contain(&key));
+
+    assert!(provider.block_event_bloom(2).unwrap().is_none());
+
+    let expected = vec![EventPosition { block_number: 0, receipt_index: 0, event_index: 0 }];
+    assert_eq!(provider.filter_events(Some(address), Some(key), 0..=1).unwrap(), expected);
+}
+
+#[test]
+fn state_root_inputs() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let provider = InMemoryProvider::new();
+    for (num, state_root) in [(0, felt!("0x100")), (1, felt!("0x101"))] {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states.state_updates.nonce_updates.insert(address, FieldElement::from(num));
+
+        let block = sealed_block(Header { state_root, ..header(num) }, vec![]);
+        provider.insert_block_with_states_and_receipts(block, states, vec![]).unwrap();
+    }
+
+    let inputs = provider.state_root_inputs(1).unwrap().unwrap();
+    assert_eq!(inputs.block_number, 1);
+    assert_eq!(inputs.parent_state_root, felt!("0x100"));
+    assert_eq!(inputs.state_root, felt!("0x101"));
+    assert_eq!(inputs.state_updates.nonce_updates, HashMap::from([(address, felt!("0x1"))]));
+
+    let genesis = provider.state_root_inputs(0).unwrap().unwrap();
+    assert_eq!(genesis.parent_state_root, FieldElement::ZERO);
+
+    assert!(provider.state_root_inputs(2).unwrap().is_none());
+}
+
+#[test]
+fn memory_budget_prunes_old_states() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let insert_blocks = |provider: &InMemoryProvider| {
+        for num in 0..10u64 {
+            let mut states = StateUpdatesWithDeclaredClasses::default();
+            let slots =
+                (0..10).map(|i| (FieldElement::from(num * 10 + i), FieldElement::from(num)));
+            states.state_updates.storage_updates.insert(address, slots.collect());
+            insert_block(provider, num, vec![], states);
+        }
+    };
+
+    let unbounded = InMemoryProvider::new().with_memory_budget(usize::MAX);
+    insert_blocks(&unbounded);
+    for num in 0..10 {
+        assert!(unbounded.historical(BlockHashOrNumber::Num(num)).unwrap().is_some());
+    }
+
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    let provider = InMemoryProvider::new().with_memory_budget(1);
+    let evicted_clone = Arc::clone(&evicted);
+    provider.set_on_evict_snapshot(Box::new(move |num: BlockNumber, _: &dyn StateProvider| {
+        evicted_clone.lock().push(num)
+    }));
+    insert_blocks(&provider);
+
+    // only the most recent historical state is kept
+    assert_eq!(*evicted.lock(), (0..9).collect::<Vec<_>>());
+    for num in 0..9 {
+        assert!(provider.historical(BlockHashOrNumber::Num(num)).unwrap().is_none());
+    }
+
+    let state = provider.historical(BlockHashOrNumber::Num(9)).unwrap().unwrap();
+    assert_eq!(state.storage(address, felt!("0x5a")).unwrap(), Some(felt!("0x9")));
+    assert_eq!(state.storage(address, felt!("0x0")).unwrap(), Some(felt!("0x0")));
+
+    let latest = provider.latest().unwrap();
+    assert_eq!(latest.storage(address, felt!("0x63")).unwrap(), Some(felt!("0x9")));
+}
+
+#[test]
+fn transaction_index_in_block() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(0); 2], Default::default());
+    insert_block(&provider, 1, vec![invoke_receipt(0); 3], Default::default());
+
+    assert_eq!(provider.transaction_index_in_block(tx_hash(1, 1)).unwrap(), Some(1));
+    assert_eq!(provider.transaction_index_in_block(tx_hash(1, 0)).unwrap(), Some(0));
+    assert_eq!(provider.transaction_index_in_block(tx_hash(0, 1)).unwrap(), Some(1));
+    assert_eq!(provider.transaction_index_in_block(felt!("0xdead")).unwrap(), None);
+}
+
+#[test]
+fn debug_dump() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![], Default::default());
+    insert_block(&provider, 1, vec![invoke_receipt(0); 2], Default::default());
+
+    let mut buf = Vec::new();
+    provider.debug_dump(&mut buf).unwrap();
+    let dump = String::from_utf8(buf).unwrap();
+
+    let lines = dump.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        vec![
+            "block=0 hash=0xb10c0000 tx_count=0 status=AcceptedOnL2 state_root=0x0",
+            "block=1 hash=0xb10c0001 tx_count=2 status=AcceptedOnL2 state_root=0x0",
+        ]
+    );
+}
+
+#[test]
+fn is_ancestor() {
+    let provider = InMemoryProvider::new();
+    for num in 0..=2 {
+        insert_block(&provider, num, vec![], Default::default());
+    }
+
+    let block = sealed_block(Header { parent_hash: felt!("0xdead"), ..header(3) }, vec![]);
+    provider.insert_block_with_states_and_receipts(block, Default::default(), vec![]).unwrap();
+
+    let num = BlockHashOrNumber::Num;
+    let hash = |num| BlockHashOrNumber::Hash(block_hash(num));
+
+    assert!(provider.is_ancestor(num(0), num(2)).unwrap());
+    assert!(provider.is_ancestor(hash(0), num(2)).unwrap());
+    assert!(provider.is_ancestor(num(1), hash(1)).unwrap());
+    assert!(!provider.is_ancestor(num(2), num(0)).unwrap());
+    // block 3 doesn't link back to block 2
+    assert!(!provider.is_ancestor(num(1), num(3)).unwrap());
+
+    let err = provider.is_ancestor(num(0), num(4)).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::BlockNotFound(BlockHashOrNumber::Num(4)))
+    ));
+}
+
+#[test]
+fn event_count_by_tx() {
+    let event = Event { from_address: felt!("0xa").into(), keys: vec![], data: vec![] };
+    let events = vec![event; 3];
+    let receipt = Receipt::Invoke(InvokeTxReceipt { events, ..Default::default() });
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(0), receipt], Default::default());
+
+    assert_eq!(provider.event_count_by_tx(tx_hash(0, 1)).unwrap(), Some(3));
+    assert_eq!(provider.event_count_by_tx(tx_hash(0, 0)).unwrap(), Some(0));
+    assert_eq!(provider.event_count_by_tx(felt!("0xdead")).unwrap(), None);
+}
+
+#[test]
+fn swap_state() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let chain = |blocks: u64, value: FieldElement| {
+        let provider = InMemoryProvider::new();
+        for num in 0..blocks {
+            let mut states = StateUpdatesWithDeclaredClasses::default();
+            states
+                .state_updates
+                .storage_updates
+                .insert(address, HashMap::from([(felt!("0x1"), value + FieldElement::from(num))]));
+            insert_block(&provider, num, vec![invoke_receipt(0); num as usize + 1], states);
+        }
+        provider
+    };
+
+    let provider = chain(3, felt!("0xa0"));
+    let latest = provider.latest().unwrap();
+    assert_eq!(latest.storage(address, felt!("0x1")).unwrap(), Some(felt!("0xa2")));
+
+    provider.swap_state(chain(2, felt!("0xb0")));
+
+    assert_eq!(latest.storage(address, felt!("0x1")).unwrap(), Some(felt!("0xb1")));
+    assert_eq!(provider.latest_number().unwrap(), 1);
+    assert_eq!(provider.transaction_by_hash(tx_hash(2, 0)).unwrap(), None);
+    assert_eq!(provider.cumulative_tx_count(1).unwrap(), Some(3));
+
+    let state = provider.historical(BlockHashOrNumber::Num(0)).unwrap().unwrap();
+    assert_eq!(state.storage(address, felt!("0x1")).unwrap(), Some(felt!("0xb0")));
+    assert!(provider.historical(BlockHashOrNumber::Num(2)).unwrap().is_none());
+
+    // the swapped in chain can be extended
+    insert_block(&provider, 2, vec![], Default::default());
+    assert_eq!(provider.latest_number().unwrap(), 2);
+    assert_eq!(latest.storage(address, felt!("0x1")).unwrap(), Some(felt!("0xb1")));
+}
+
+#[test]
+fn compiled_class_hashes() {
+    let mut states = StateUpdatesWithDeclaredClasses::default();
+    states.state_updates.declared_classes.insert(felt!("0x1"), felt!("0x10"));
+    states.state_updates.declared_classes.insert(felt!("0x2"), felt!("0x20"));
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![], states);
+
+    let hashes = [felt!("0x2"), felt!("0x3"), felt!("0x1")];
+    assert_eq!(
+        provider.compiled_class_hashes(&hashes).unwrap(),
+        vec![Some(felt!("0x20")), None, Some(felt!("0x10"))]
+    );
+    assert!(provider.compiled_class_hashes(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn insert_block_no_history() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    for strategy in [SnapshotStrategy::Full, SnapshotStrategy::Diff] {
+        let provider = InMemoryProvider::new().with_snapshot_strategy(strategy);
+        insert_block(&provider, 0, vec![], Default::default());
+
+        for num in 1..=3 {
+            let mut states = StateUpdatesWithDeclaredClasses::default();
+            states
+                .state_updates
+                .storage_updates
+                .insert(address, HashMap::from([(felt!("0x1"), FieldElement::from(num))]));
+
+            let block = sealed_block(header(num), invoke_txs(num, 1));
+            provider.insert_block_no_history(block, states, vec![invoke_receipt(0)]).unwrap();
+        }
+
+        assert_eq!(provider.latest_number().unwrap(), 3);
+        assert!(provider.transaction_by_hash(tx_hash(2, 0)).unwrap().is_some());
+
+        let latest = provider.latest().unwrap();
+        assert_eq!(latest.storage(address, felt!("0x1")).unwrap(), Some(felt!("0x3")));
+
+        assert!(provider.historical(BlockHashOrNumber::Num(0)).unwrap().is_some());
+        for num in 1..=3 {
+            assert!(provider.historical(BlockHashOrNumber::Num(num)).unwrap().is_none());
+        }
+
+        // the blocks inserted afterwards have their history again
+        insert_block(&provider, 4, vec![], Default::default());
+        let state = provider.historical(BlockHashOrNumber::Num(4)).unwrap().unwrap();
+        assert_eq!(state.storage(address, felt!("0x1")).unwrap(), Some(felt!("0x3")));
+    }
+}
+
+#[test]
+fn declared_classes_diff() {
+    let provider = InMemoryProvider::new();
+    let other = InMemoryProvider::new();
+    for hash in [felt!("0x1"), felt!("0x2"), felt!("0x3")] {
+        provider.set_class(hash, (*ERC20_CONTRACT).clone()).unwrap();
+    }
+    for hash in [felt!("0x2"), felt!("0x4")] {
+        other.set_class(hash, (*ERC20_CONTRACT).clone()).unwrap();
+    }
+
+    assert_eq!(provider.declared_classes_diff(&other).unwrap(), vec![felt!("0x1"), felt!("0x3")]);
+    assert_eq!(other.declared_classes_diff(&provider).unwrap(), vec![felt!("0x4")]);
+    assert!(provider.declared_classes_diff(&provider).unwrap().is_empty());
+}
+
+#[test]
+fn finalized_state() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let provider = InMemoryProvider::new();
+    let insert = |num: BlockNumber, status: FinalityStatus| {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states.state_updates.nonce_updates.insert(address, FieldElement::from(num));
+
+        let block = SealedBlockWithStatus { status, ..sealed_block(header(num), vec![]) };
+        provider.insert_block_with_states_and_receipts(block, states, vec![]).unwrap();
+    };
+
+    insert(0, FinalityStatus::AcceptedOnL2);
+    assert_eq!(provider.finalized_block_number().unwrap(), None);
+    assert!(provider.finalized_state().unwrap().is_none());
+
+    insert(1, FinalityStatus::AcceptedOnL1);
+    insert(2, FinalityStatus::AcceptedOnL1);
+    insert(3, FinalityStatus::AcceptedOnL2);
+
+    assert_eq!(provider.finalized_block_number().unwrap(), Some(2));
+    let state = provider.finalized_state().unwrap().unwrap();
+    assert_eq!(state.nonce(address).unwrap(), Some(felt!("0x2")));
+    assert_eq!(provider.latest().unwrap().nonce(address).unwrap(), Some(felt!("0x3")));
+}
+
+#[test]
+fn growth_stats() {
+    let provider = InMemoryProvider::new();
+    assert_eq!(provider.growth_stats(10).unwrap().blocks, 0);
+
+    for (num, tx_count) in [(0, 10), (1, 1), (2, 2), (3, 6)] {
+        insert_block(&provider, num, vec![invoke_receipt(0); tx_count], Default::default());
+    }
+
+    let stats = provider.growth_stats(3).unwrap();
+    assert_eq!(stats.blocks, 3);
+    assert_eq!(stats.avg_txs_per_block, 3.0);
+
+    // the window is clamped to the blocks available
+    let stats = provider.growth_stats(100).unwrap();
+    assert_eq!(stats.blocks, 4);
+    assert_eq!(stats.avg_txs_per_block, 4.75);
+
+    // bigger blocks are estimated to use more memory
+    let small = provider.growth_stats(1).unwrap().avg_bytes_per_block;
+    insert_block(&provider, 4, vec![invoke_receipt(0); 20], Default::default());
+    let big = provider.growth_stats(1).unwrap().avg_bytes_per_block;
+    assert!(small > 0.0 && big > small);
+}
+
+#[test]
+fn transaction_signature() {
+    let invoke = InvokeTx { signature: vec![felt!("0x1"), felt!("0x2")], ..Default::default() };
+    let l1_handler = L1HandlerTx {
+        nonce: Default::default(),
+        chain_id: Default::default(),
+        paid_fee_on_l1: 0,
+        version: Default::default(),
+        message_hash: Default::default(),
+        calldata: vec![],
+        contract_address: Default::default(),
+        entry_point_selector: Default::default(),
+    };
+
+    let body = vec![
+        TxWithHash { hash: tx_hash(0, 0), transaction: Tx::Invoke(invoke) },
+        TxWithHash { hash: tx_hash(0, 1), transaction: Tx::L1Handler(l1_handler) },
+    ];
+    let block = sealed_block(header(0), body);
+
+    let provider = InMemoryProvider::new();
+    provider
+        .insert_block_with_states_and_receipts(
+            block,
+            Default::default(),
+            vec![invoke_receipt(0); 2],
+        )
+        .unwrap();
+
+    assert_eq!(
+        provider.transaction_signature(tx_hash(0, 0)).unwrap(),
+        Some(vec![felt!("0x1"), felt!("0x2")])
+    );
+    assert_eq!(provider.transaction_signature(tx_hash(0, 1)).unwrap(), None);
+    assert_eq!(provider.transaction_signature(felt!("0xdead")).unwrap(), None);
+}
+
+#[test]
+fn full_latest_state() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let mut states = StateUpdatesWithDeclaredClasses::default();
+    states.state_updates.nonce_updates.insert(address, felt!("0x3"));
+    states.state_updates.contract_updates.insert(address, felt!("0xc1a55"));
+    states.state_updates.storage_updates.insert(
+        address,
+        HashMap::from([(felt!("0x1"), felt!("0x10")), (felt!("0x2"), felt!("0x20"))]),
+    );
+    states.state_updates.declared_classes.insert(felt!("0xc1a55"), felt!("0xcaca0"));
+    states.declared_compiled_classes.insert(felt!("0xc1a55"), (*ERC20_CONTRACT).clone());
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![], states);
+    provider.set_class(felt!("0x1e9ac1"), (*ERC20_CONTRACT).clone()).unwrap();
+
+    let full = provider.full_latest_state().unwrap();
+    let latest = provider.latest().unwrap();
+
+    assert_eq!(full.contracts.len(), 1);
+    assert_eq!(Some(full.contracts[&address].nonce), latest.nonce(address).unwrap());
+    assert_eq!(
+        Some(full.contracts[&address].class_hash),
+        latest.class_hash_of_contract(address).unwrap()
+    );
+
+    for (key, value) in &full.storage[&address] {
+        assert_eq!(latest.storage(address, *key).unwrap(), Some(*value));
+    }
+    assert_eq!(full.storage[&address].len(), 2);
+
+    assert_eq!(full.compiled_class_hashes, HashMap::from([(felt!("0xc1a55"), felt!("0xcaca0"))]));
+    assert_eq!(full.declared_classes, vec![felt!("0xc1a55"), felt!("0x1e9ac1")]);
+}
+
+#[test]
+fn slot_first_nonzero_at() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let provider = InMemoryProvider::new();
+    for (num, value) in [
+        (0, felt!("0x0")),
+        (1, felt!("0x0")),
+        (2, felt!("0x5")),
+        (3, felt!("0x0")),
+        (4, felt!("0x6")),
+    ] {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states
+            .state_updates
+            .storage_updates
+            .insert(address, HashMap::from([(felt!("0x1"), value)]));
+        insert_block(&provider, num, vec![], states);
+    }
+
+    let mut states = StateUpdatesWithDeclaredClasses::default();
+    states
+        .state_updates
+        .storage_updates
+        .insert(address, HashMap::from([(felt!("0x2"), felt!("0x0"))]));
+    insert_block(&provider, 5, vec![], states);
+
+    assert_eq!(provider.slot_first_nonzero_at(address, felt!("0x1")).unwrap(), Some(2));
+    assert_eq!(provider.slot_first_nonzero_at(address, felt!("0x2")).unwrap(), None);
+    assert_eq!(provider.slot_first_nonzero_at(felt!("0x1").into(), felt!("0x1")).unwrap(), None);
+}
+
+#[test]
+fn block_events() {
+    let event =
+        |n: u8| Event { from_address: felt!("0xa").into(), keys: vec![n.into()], data: vec![] };
+    let receipt = |events| Receipt::Invoke(InvokeTxReceipt { events, ..Default::default() });
+
+    let provider = InMemoryProvider::new();
+    let receipts =
+        vec![receipt(vec![event(1), event(2)]), receipt(vec![]), receipt(vec![event(3)])];
+    insert_block(&provider, 0, receipts, Default::default());
+    insert_block(&provider, 1, vec![invoke_receipt(0)], Default::default());
+
+    let emitted = |tx: usize, event_index: u64, n: u8| EmittedEvent {
+        transaction_hash: tx_hash(0, tx),
+        transaction_index: tx as u64,
+        event_index,
+        event: event(n),
+    };
+
+    assert_eq!(
+        provider.block_events(0).unwrap(),
+        Some(vec![emitted(0, 0, 1), emitted(0, 1, 2), emitted(2, 0, 3)])
+    );
+    assert_eq!(provider.block_events(1).unwrap(), Some(vec![]));
+    assert_eq!(provider.block_events(2).unwrap(), None);
+}
+
+#[test]
+fn sealed_blocks_in_range() {
+    let provider = InMemoryProvider::new();
+    let mut originals = Vec::new();
+    for (num, tx_count, status) in [
+        (0, 1, FinalityStatus::AcceptedOnL1),
+        (1, 0, FinalityStatus::AcceptedOnL2),
+        (2, 3, FinalityStatus::AcceptedOnL2),
+    ] {
+        let header = Header { timestamp: 100 + num, ..header(num) };
+        let block =
+            SealedBlockWithStatus { status, ..sealed_block(header, invoke_txs(num, tx_count)) };
+
+        originals.push(block.clone());
+        provider
+            .insert_block_with_states_and_receipts(
+                block,
+                Default::default(),
+                vec![invoke_receipt(0); tx_count],
+            )
+            .unwrap();
+    }
+
+    let blocks = provider.sealed_blocks_in_range(1..=5).unwrap();
+    assert_eq!(blocks.len(), 2);
+    for (actual, expected) in blocks.iter().zip(&originals[1..]) {
+        assert_eq!(actual.status, expected.status);
+        assert_eq!(actual.block.header.hash, expected.block.header.hash);
+        assert_eq!(actual.block.header.header, expected.block.header.header);
+        assert_eq!(actual.block.body, expected.block.body);
+    }
+}
+
+#[test]
+fn deploy_transactions_in_range() {
+    let account = ContractAddress::from(felt!("0xacc"));
+    let deployed = ContractAddress::from(felt!("0xde9"));
+    let spoofed = ContractAddress::from(felt!("0xbad"));
+    let undeployed = ContractAddress::from(felt!("0x404"));
+
+    let deploy_account = DeployAccountTx { contract_address: account, ..Default::default() };
+    let deployed_event = |from_address: ContractAddress, address: ContractAddress| Event {
+        from_address,
+        keys: vec![selector!("ContractDeployed")],
+        data: vec![*address, felt!("0x1")],
+    };
+
+    let body = vec![
+        TxWithHash { hash: tx_hash(0, 0), transaction: Tx::DeployAccount(deploy_account) },
+        TxWithHash { hash: tx_hash(0, 1), transaction: Tx::Invoke(Default::default()) },
+        TxWithHash { hash: tx_hash(0, 2), transaction: Tx::Invoke(Default::default()) },
+        TxWithHash { hash: tx_hash(0, 3), transaction: Tx::Invoke(Default::default()) },
+    ];
+    let receipts = vec![
+        Receipt::DeployAccount(DeployAccountTxReceipt {
+            actual_fee: 0,
+            events: vec![],
+            messages_sent: vec![],
+            revert_error: None,
+            execution_resources: Default::default(),
+            contract_address: account,
+        }),
+        Receipt::Invoke(InvokeTxReceipt {
+            events: vec![
+                deployed_event(UDC_ADDRESS, deployed),
+                deployed_event(UDC_ADDRESS, undeployed),
+            ],
+            ..Default::default()
+        }),
+        invoke_receipt(0),
+        // a `ContractDeployed` event emitted by a contract other than the UDC.
+        Receipt::Invoke(InvokeTxReceipt {
+            events: vec![deployed_event(spoofed, spoofed)],
+            ..Default::default()
+        }),
+    ];
+
+    let mut states = StateUpdatesWithDeclaredClasses::default();
+    states.state_updates.contract_updates.insert(account, felt!("0xc1a55"));
+    states.state_updates.contract_updates.insert(deployed, felt!("0xc1a55"));
+    states.state_updates.contract_updates.insert(spoofed, felt!("0xc1a55"));
+
+    let provider = InMemoryProvider::new();
+    let block = sealed_block(header(0), body);
+    provider.insert_block_with_states_and_receipts(block, states, receipts).unwrap();
+    insert_block(&provider, 1, vec![invoke_receipt(0)], Default::default());
+
+    assert_eq!(
+        provider.deploy_transactions_in_range(0..=2).unwrap(),
+        vec![(tx_hash(0, 0), account), (tx_hash(0, 1), deployed)]
+    );
+    assert!(provider.deploy_transactions_in_range(1..=1).unwrap().is_empty());
+}
+
+#[test]
+fn historical_states_in_range() {
+    let address = ContractAddress::from(felt!("0x1337"));
+    let states = |num: u64| {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states
+            .state_updates
+            .storage_updates
+            .insert(address, HashMap::from([(felt!("0x1"), FieldElement::from(num))]));
+        states
+    };
+
+    for strategy in [SnapshotStrategy::Full, SnapshotStrategy::Diff] {
+        let provider = InMemoryProvider::new().with_snapshot_strategy(strategy);
+        insert_block(&provider, 0, vec![], states(0));
+
+        // blocks 1 and 2 have no historical state
+        for num in 1..=2 {
+            let block = sealed_block(header(num), vec![]);
+            provider.insert_block_no_history(block, states(num), vec![]).unwrap();
+        }
+
+        insert_block(&provider, 3, vec![], states(3));
+        insert_block(&provider, 4, vec![], states(4));
+
+        let historical = provider.historical_states_in_range(0..=6).unwrap();
+        let numbers = historical.iter().map(|(num, _)| *num).collect::<Vec<_>>();
+        assert_eq!(numbers, vec![0, 3, 4]);
+
+        for (num, state) in historical {
+            assert_eq!(
+                state.storage(address, felt!("0x1")).unwrap(),
+                Some(FieldElement::from(num))
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn follow_from() {
+    let provider = Arc::new(InMemoryProvider::new());
+    for num in 0..=2 {
+        insert_block(&provider, num, vec![], Default::default());
+    }
+
+    let mut stream = Box::pin(provider.follow_from(1));
+
+    // catch up to the current tip
+    for num in 1..=2 {
+        let block = stream.next().await.unwrap().unwrap();
+        assert_eq!(block.header.number, num);
+    }
+
+    let writer = tokio::spawn({
+        let provider = Arc::clone(&provider);
+        async move {
+            tokio::task::yield_now().await;
+            insert_block(&provider, 3, vec![], Default::default());
+        }
+    });
+
+    // then follow the newly inserted blocks
+    let block = tokio::time::timeout(Duration::from_secs(5), stream.next()).await.unwrap();
+    assert_eq!(block.unwrap().unwrap().header.number, 3);
+    writer.await.unwrap();
+}
+
+#[test]
+fn account_snapshot() {
+    let provider = InMemoryProvider::new();
+    let address = ContractAddress::from(felt!("0xacc"));
+    provider.set_class_hash_of_contract(address, felt!("0xc1a55")).unwrap();
+    provider.set_nonce(address, felt!("0x2")).unwrap();
+    provider.set_storage(address, felt!("0x1"), felt!("0x10")).unwrap();
+    provider.set_storage(address, felt!("0x2"), felt!("0x20")).unwrap();
+    provider.set_storage(address, felt!("0x3"), FieldElement::ZERO).unwrap();
+
+    assert_eq!(
+        provider.account_snapshot(address).unwrap(),
+        AccountSnapshot {
+            class_hash: felt!("0xc1a55"),
+            nonce: felt!("0x2"),
+            storage_slot_count: 2
+        }
+    );
+    assert_eq!(
+        provider.account_snapshot(felt!("0xdead").into()).unwrap(),
+        AccountSnapshot::default()
+    );
+}
+
+#[test]
+fn insert_block_with_state_root() {
+    // a stand-in for the state root computation, folding the storage values into the parent root
+    let compute_state_root = |parent_root: FieldElement, updates: &StateUpdates| {
+        updates
+            .storage_updates
+            .values()
+            .flat_map(|slots| slots.values())
+            .fold(parent_root, |a, b| a + *b)
+    };
+
+    let address = ContractAddress::from(felt!("0x1337"));
+    let states = |value: FieldElement| {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states
+            .state_updates
+            .storage_updates
+            .insert(address, HashMap::from([(felt!("0x1"), value)]));
+        states
+    };
+    let block = |num: BlockNumber| sealed_block(header(num), vec![]);
+
+    // without validation, any state root is stored as is
+    let provider = InMemoryProvider::new();
+    provider
+        .insert_block_with_state_root(block(0), states(felt!("0x2")), vec![], felt!("0x2"))
+        .unwrap();
+    provider
+        .insert_block_with_state_root(block(1), states(felt!("0x3")), vec![], felt!("0xbad"))
+        .unwrap();
+    assert_eq!(provider.state_root(BlockHashOrNumber::Num(0)).unwrap(), Some(felt!("0x2")));
+    assert_eq!(provider.state_root(BlockHashOrNumber::Num(1)).unwrap(), Some(felt!("0xbad")));
+
+    // with validation, only the blocks committing to the computed state root are inserted
+    let provider = InMemoryProvider::new().with_state_root_validation(Box::new(compute_state_root));
+    provider
+        .insert_block_with_state_root(block(0), states(felt!("0x2")), vec![], felt!("0x2"))
+        .unwrap();
+
+    let err = provider
+        .insert_block_with_state_root(block(1), states(felt!("0x3")), vec![], felt!("0xbad"))
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::StateRootMismatch { number: 1, expected, actual })
+            if *expected == felt!("0x5") && *actual == felt!("0xbad")
+    ));
+    assert_eq!(provider.latest_number().unwrap(), 0);
+    let latest = provider.latest().unwrap();
+    assert_eq!(latest.storage(address, felt!("0x1")).unwrap(), Some(felt!("0x2")));
+
+    provider
+        .insert_block_with_state_root(block(1), states(felt!("0x3")), vec![], felt!("0x5"))
+        .unwrap();
+    assert_eq!(provider.state_root(BlockHashOrNumber::Num(1)).unwrap(), Some(felt!("0x5")));
+}
+
+#[test]
+fn insert_block_with_mismatched_receipts() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(1)], Default::default());
+
+    let block = sealed_block(header(1), invoke_txs(1, 2));
+    let err = provider
+        .insert_block_with_states_and_receipts(block, Default::default(), vec![invoke_receipt(1)])
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::ReceiptCountMismatch {
+            number: 1,
+            transactions: 2,
+            receipts: 1
+        })
+    ));
+
+    // nothing of the rejected block is stored
+    assert_eq!(provider.latest_number().unwrap(), 0);
+    assert_eq!(provider.block_hash_by_num(1).unwrap(), None);
+    assert!(provider.transaction_by_hash(tx_hash(1, 0)).unwrap().is_none());
+    assert_eq!(provider.transaction_statuses(&[tx_hash(1, 0)]).unwrap(), vec![None]);
+
+    insert_block(&provider, 1, vec![invoke_receipt(2), invoke_receipt(3)], Default::default());
+    assert_eq!(provider.total_fees_in_range(0..=1).unwrap(), FieldElement::from(6u8));
+}
+
+#[test]
+fn all_transaction_hashes() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(0); 2], Default::default());
+    insert_block(&provider, 1, vec![], Default::default());
+    insert_block(&provider, 2, vec![invoke_receipt(0); 3], Default::default());
+
+    let mut expected = Vec::new();
+    for num in 0..=2 {
+        let txs = provider.transactions_by_block(BlockHashOrNumber::Num(num)).unwrap().unwrap();
+        expected.extend(txs.into_iter().map(|tx| tx.hash));
+    }
+
+    assert_eq!(expected.len(), 5);
+    assert_eq!(provider.all_transaction_hashes().unwrap(), expected);
+}
+
+#[test]
+fn revert_block_returning() {
+    let address = ContractAddress::from(felt!("0x1337"));
+    let class_hash = felt!("0xc1");
+
+    let provider = InMemoryProvider::new().with_event_index(true);
+    for num in 0..=3 {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states
+            .state_updates
+            .storage_updates
+            .insert(address, HashMap::from([(felt!("0x1"), FieldElement::from(num + 1))]));
+        if num == 2 {
+            states.state_updates.declared_classes.insert(class_hash, felt!("0xcc1"));
+            states.declared_compiled_classes.insert(class_hash, (*ERC20_CONTRACT).clone());
+        }
+        insert_block(&provider, num, vec![invoke_receipt(num as u128); 2], states);
+    }
+
+    let removed = provider.revert_block_returning(1).unwrap();
+    let numbers = removed.iter().map(|(block, ..)| block.block.header.header.number);
+    assert_eq!(numbers.collect::<Vec<_>>(), vec![3, 2]);
+    assert_eq!(removed[0].2, vec![invoke_receipt(3); 2]);
+    assert_eq!(removed[1].1.declared_compiled_classes.len(), 1);
+
+    assert_eq!(provider.latest_number().unwrap(), 1);
+    assert_eq!(provider.latest_hash().unwrap(), block_hash(1));
+    assert_eq!(provider.transaction_by_hash(tx_hash(2, 0)).unwrap(), None);
+    assert_eq!(provider.all_transaction_hashes().unwrap().len(), 4);
+    assert!(provider.historical(BlockHashOrNumber::Num(2)).unwrap().is_none());
+
+    let latest = provider.latest().unwrap();
+    assert_eq!(latest.storage(address, felt!("0x1")).unwrap(), Some(felt!("0x2")));
+    assert_eq!(latest.compiled_class_hash_of_class_hash(class_hash).unwrap(), None);
+
+    // the removed blocks can be inserted back as they were
+    for (block, states, receipts) in removed.into_iter().rev() {
+        provider.insert_block_with_states_and_receipts(block, states, receipts).unwrap();
+    }
+
+    assert_eq!(provider.latest_number().unwrap(), 3);
+    assert_eq!(provider.block_hash_by_num(2).unwrap(), Some(block_hash(2)));
+    assert_eq!(
+        provider.receipts_by_block(BlockHashOrNumber::Num(3)).unwrap(),
+        Some(vec![invoke_receipt(3); 2])
+    );
+
+    let latest = provider.latest().unwrap();
+    assert_eq!(latest.storage(address, felt!("0x1")).unwrap(), Some(felt!("0x4")));
+    assert_eq!(latest.class(class_hash).unwrap(), Some((*ERC20_CONTRACT).clone()));
+
+    let state = provider.historical(BlockHashOrNumber::Num(2)).unwrap().unwrap();
+    assert_eq!(state.storage(address, felt!("0x1")).unwrap(), Some(felt!("0x3")));
+
+    let err = provider.revert_block_returning(10).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::BlockNotFound(BlockHashOrNumber::Num(10)))
+    ));
+
+    // a class declared again in a removed block stays available to the remaining blocks
+    let mut states = StateUpdatesWithDeclaredClasses::default();
+    states.state_updates.declared_classes.insert(class_hash, felt!("0xcc1"));
+    states.declared_compiled_classes.insert(class_hash, (*ERC20_CONTRACT).clone());
+    insert_block(&provider, 4, vec![], states);
+
+    let removed = provider.revert_block_returning(3).unwrap();
+    assert_eq!(removed[0].1.declared_compiled_classes.len(), 1);
+
+    let latest = provider.latest().unwrap();
+    assert_eq!(latest.class(class_hash).unwrap(), Some((*ERC20_CONTRACT).clone()));
+    let state = provider.historical(BlockHashOrNumber::Num(2)).unwrap().unwrap();
+    assert_eq!(state.class(class_hash).unwrap(), Some((*ERC20_CONTRACT).clone()));
+}
+
+#[test]
+fn class_hash_by_compiled() {
+    let provider = InMemoryProvider::new();
+
+    let mut states = StateUpdatesWithDeclaredClasses::default();
+    states.state_updates.declared_classes.insert(felt!("0xc1"), felt!("0xcc1"));
+    insert_block(&provider, 0, vec![], states);
+
+    provider.set_compiled_class_hash_of_class_hash(felt!("0xc2"), felt!("0xcc2")).unwrap();
+    // a second class with the same compiled class hash doesn't replace the first one
+    provider.set_compiled_class_hash_of_class_hash(felt!("0xc3"), felt!("0xcc1")).unwrap();
+
+    assert_eq!(provider.class_hash_by_compiled(felt!("0xcc1")).unwrap(), Some(felt!("0xc1")));
+    assert_eq!(provider.class_hash_by_compiled(felt!("0xcc2")).unwrap(), Some(felt!("0xc2")));
+    assert_eq!(provider.class_hash_by_compiled(felt!("0xcc3")).unwrap(), None);
+}
+
+#[test]
+fn try_latest_number_timeout() {
+    let provider = InMemoryProvider::new();
+    let timeout = Duration::from_millis(10);
+    assert_eq!(provider.try_latest_number_timeout(timeout).unwrap(), None);
+
+    insert_block(&provider, 0, vec![], Default::default());
+    insert_block(&provider, 1, vec![], Default::default());
+    assert_eq!(provider.try_latest_number_timeout(timeout).unwrap(), Some(1));
+
+    // a writer holding the lock makes the read time out
+    let _guard = provider.storage.write();
+    let err = provider.try_latest_number_timeout(timeout).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::LockTimeout(dur)) if *dur == timeout
+    ));
+}
+
+#[test]
+fn cumulative_event_count() {
+    let event = Event { from_address: felt!("0x1").into(), keys: vec![], data: vec![] };
+    let receipt = |events: usize| {
+        Receipt::Invoke(InvokeTxReceipt {
+            events: vec![event.clone(); events],
+            ..Default::default()
+        })
+    };
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![receipt(2), receipt(1)], Default::default());
+    insert_block(&provider, 1, vec![], Default::default());
+    insert_block(&provider, 2, vec![receipt(0), receipt(4)], Default::default());
+
+    let counts =
+        (0..=2).map(|num| provider.cumulative_event_count(num).unwrap()).collect::<Vec<_>>();
+    assert_eq!(counts, vec![Some(3), Some(3), Some(7)]);
+    assert_eq!(provider.cumulative_event_count(3).unwrap(), None);
+}
+
+#[test]
+fn blocks_by_numbers() {
+    let provider = InMemoryProvider::new();
+    for num in 0..=3 {
+        insert_block(&provider, num, vec![invoke_receipt(0); num as usize], Default::default());
+    }
+
+    let blocks = provider.blocks_by_numbers(&[3, 7, 0, 2, 3]).unwrap();
+    let expected = [Some(3), None, Some(0), Some(2), Some(3)]
+        .map(|num| num.map(|num| provider.block(BlockHashOrNumber::Num(num)).unwrap().unwrap()));
+    assert_eq!(blocks, expected);
+    assert_eq!(blocks[0].as_ref().unwrap().body.len(), 3);
+}
+
+#[test]
+fn find_duplicate_transaction_hashes() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(0); 2], Default::default());
+    insert_block(&provider, 1, vec![invoke_receipt(0); 2], Default::default());
+    assert!(provider.find_duplicate_transaction_hashes().unwrap().is_empty());
+
+    // the insertion doesn't check the hashes, so a block can reuse the hash of a transaction
+    let mut body = invoke_txs(2, 2);
+    body[1].hash = tx_hash(0, 0);
+    let block = sealed_block(header(2), body);
+    let receipts = vec![invoke_receipt(0); 2];
+    provider.insert_block_with_states_and_receipts(block, Default::default(), receipts).unwrap();
+    assert_eq!(provider.find_duplicate_transaction_hashes().unwrap(), vec![tx_hash(0, 0)]);
+}
+
+#[test]
+fn latest_state_update() {
+    let provider = InMemoryProvider::new();
+    assert_eq!(provider.latest_state_update().unwrap(), None);
+
+    let address = ContractAddress::from(felt!("0x1337"));
+    for num in 0..=2 {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states.state_updates.nonce_updates.insert(address, FieldElement::from(num));
+        insert_block(&provider, num, vec![], states);
+    }
+
+    let latest = provider.latest_state_update().unwrap();
+    assert_eq!(latest, provider.state_update(BlockHashOrNumber::Num(2)).unwrap());
+    assert_eq!(latest.unwrap().nonce_updates.get(&address), Some(&felt!("0x2")));
+}
+
+#[test]
+fn contracts_with_class() {
+    let provider = InMemoryProvider::new();
+    provider.set_class_hash_of_contract(felt!("0x2").into(), felt!("0xc1")).unwrap();
+    provider.set_class_hash_of_contract(felt!("0x1").into(), felt!("0xc1")).unwrap();
+    provider.set_class_hash_of_contract(felt!("0x3").into(), felt!("0xc2")).unwrap();
+
+    assert_eq!(
+        provider.contracts_with_class(felt!("0xc1")).unwrap(),
+        vec![felt!("0x1").into(), felt!("0x2").into()]
+    );
+    assert_eq!(provider.contracts_with_class(felt!("0xc2")).unwrap(), vec![felt!("0x3").into()]);
+    assert!(provider.contracts_with_class(felt!("0xc3")).unwrap().is_empty());
+}
+
+#[test]
+fn contract_storage_keys_changed_in_block() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let mut states = StateUpdatesWithDeclaredClasses::default();
+    states.state_updates.storage_updates.insert(
+        address,
+        HashMap::from([(felt!("0x2"), felt!("0x20")), (felt!("0x1"), felt!("0x10"))]),
+    );
+    states
+        .state_updates
+        .storage_updates
+        .insert(felt!("0x1338").into(), HashMap::from([(felt!("0x3"), felt!("0x30"))]));
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![], states);
+    insert_block(&provider, 1, vec![], Default::default());
+
+    assert_eq!(
+        provider.contract_storage_keys_changed_in_block(address, 0).unwrap(),
+        vec![felt!("0x1"), felt!("0x2")]
+    );
+    assert!(provider.contract_storage_keys_changed_in_block(address, 1).unwrap().is_empty());
+
+    let err = provider.contract_storage_keys_changed_in_block(address, 2).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::BlockNotFound(BlockHashOrNumber::Num(2)))
+    ));
+}
+
+#[test]
+fn block_timestamps_in_range() {
+    let provider = InMemoryProvider::new();
+    for num in 0..=3 {
+        let block = sealed_block(Header { timestamp: 1_000 + num * 6, ..header(num) }, vec![]);
+        provider.insert_block_with_states_and_receipts(block, Default::default(), vec![]).unwrap();
+    }
+
+    assert_eq!(
+        provider.block_timestamps_in_range(1..=5).unwrap(),
+        vec![(1, 1_006), (2, 1_012), (3, 1_018)]
+    );
+}
+
+#[test]
+fn export_and_import_body_indices() {
+    let provider = InMemoryProvider::new();
+    for num in 0..300 {
+        insert_block(
+            &provider,
+            num,
+            vec![invoke_receipt(0); (num % 7) as usize],
+            Default::default(),
+        );
+    }
+
+    let bytes = provider.export_body_indices().unwrap();
+    let indices = InMemoryProvider::import_body_indices(&bytes).unwrap();
+
+    let numbers = indices.iter().map(|(num, _)| *num).collect::<Vec<_>>();
+    assert_eq!(numbers, (0..300).collect::<Vec<_>>());
+    for (num, indices) in indices {
+        let expected = provider.storage.read().block_body_indices.get(&num).cloned().unwrap();
+        assert_eq!(indices.tx_offset, expected.tx_offset);
+        assert_eq!(indices.tx_count, expected.tx_count);
+    }
+
+    // the consecutive blocks encode to one byte per field
+    assert!(bytes.len() <= 300 * 3 + 2);
+}
+
+#[test]
+fn last_transactions_of_block() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(0); 2], Default::default());
+    insert_block(&provider, 1, vec![invoke_receipt(0); 5], Default::default());
+
+    let hashes = |txs: Option<Vec<TxWithHash>>| {
+        txs.unwrap().into_iter().map(|tx| tx.hash).collect::<Vec<_>>()
+    };
+
+    let txs = provider.last_transactions_of_block(BlockHashOrNumber::Num(1), 3).unwrap();
+    assert_eq!(hashes(txs), vec![tx_hash(1, 4), tx_hash(1, 3), tx_hash(1, 2)]);
+
+    let txs =
+        provider.last_transactions_of_block(BlockHashOrNumber::Hash(block_hash(0)), 3).unwrap();
+    assert_eq!(hashes(txs), vec![tx_hash(0, 1), tx_hash(0, 0)]);
+
+    assert_eq!(provider.last_transactions_of_block(BlockHashOrNumber::Num(2), 3).unwrap(), None);
+}
+
+#[test]
+fn drain_to() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(1)], Default::default());
+    insert_block(&provider, 1, vec![invoke_receipt(2), invoke_receipt(3)], Default::default());
+    provider.set_class(felt!("0xc1"), (*ERC20_CONTRACT).clone()).unwrap();
+    provider.set_compiled_class_hash_of_class_hash(felt!("0xc1"), felt!("0xcc1")).unwrap();
+
+    let mut records = Vec::new();
+    provider
+        .drain_to(|record| {
+            records.push(record);
+            Ok(())
+        })
+        .unwrap();
+
+    let kinds = records
+        .iter()
+        .map(|record| match record {
+            PersistRecord::Header { header, .. } => format!("header {}", header.number),
+            PersistRecord::Transaction { tx_number, .. } => format!("tx {tx_number}"),
+            PersistRecord::Receipt { tx_number, .. } => format!("receipt {tx_number}"),
+            PersistRecord::StateUpdate { block_number, .. } => format!("state {block_number}"),
+            PersistRecord::Class { class_hash, .. } => format!("class {class_hash:#x}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        vec![
+            "header 0",
+            "tx 0",
+            "receipt 0",
+            "state 0",
+            "header 1",
+            "tx 1",
+            "receipt 1",
+            "tx 2",
+            "receipt 2",
+            "state 1",
+            "class 0xc1",
+        ]
+    );
+
+    let PersistRecord::Receipt { receipt, .. } = &records[8] else { panic!("expected a receipt") };
+    assert_eq!(receipt, &invoke_receipt(3));
+    let PersistRecord::Class { compiled_class_hash, .. } = &records[10] else {
+        panic!("expected a class")
+    };
+    assert_eq!(compiled_class_hash, &Some(felt!("0xcc1")));
+
+    // an error from the sink aborts the drain
+    let mut count = 0;
+    let result = provider.drain_to(|_| {
+        count += 1;
+        if count == 3 {
+            Err(anyhow::anyhow!("sink failed"))
+        } else {
+            Ok(())
+        }
+    });
+    assert!(result.is_err());
+    assert_eq!(count, 3);
+}
+
+#[test]
+fn is_block_pruned() {
+    let unbounded = InMemoryProvider::new();
+    let provider = InMemoryProvider::new().with_memory_budget(1);
+    for num in 0..5 {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states.state_updates.nonce_updates.insert(felt!("0x1").into(), FieldElement::from(num));
+        insert_block(&unbounded, num, vec![], states.clone());
+        insert_block(&provider, num, vec![], states);
+    }
+
+    for num in 0..5 {
+        assert!(!unbounded.is_block_pruned(num).unwrap());
+    }
+
+    // only the most recent historical state is kept
+    for num in 0..4 {
+        assert!(provider.is_block_pruned(num).unwrap());
+        assert!(provider.historical(BlockHashOrNumber::Num(num)).unwrap().is_none());
+    }
+    assert!(!provider.is_block_pruned(4).unwrap());
+    assert!(!provider.is_block_pruned(5).unwrap());
+}
+
+#[test]
+fn transaction_statuses() {
+    let reverted = Receipt::Invoke(InvokeTxReceipt {
+        revert_error: Some("reverted".to_string()),
+        ..Default::default()
+    });
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(0), reverted], Default::default());
+
+    let block = SealedBlockWithStatus {
+        status: FinalityStatus::AcceptedOnL1,
+        ..sealed_block(header(1), invoke_txs(1, 1))
+    };
+    provider
+        .insert_block_with_states_and_receipts(block, Default::default(), vec![invoke_receipt(0)])
+        .unwrap();
+
+    let statuses = provider
+        .transaction_statuses(&[tx_hash(1, 0), felt!("0xdead"), tx_hash(0, 1), tx_hash(0, 0)])
+        .unwrap();
+    assert_eq!(
+        statuses,
+        vec![
+            Some((ExecutionStatus::Succeeded, FinalityStatus::AcceptedOnL1)),
+            None,
+            Some((ExecutionStatus::Reverted, FinalityStatus::AcceptedOnL2)),
+            Some((ExecutionStatus::Succeeded, FinalityStatus::AcceptedOnL2)),
+        ]
+    );
+}
+
+#[test]
+fn block_hash_root() {
+    let provider = InMemoryProvider::new();
+    for num in 0..=3 {
+        insert_block(&provider, num, vec![], Default::default());
+    }
+
+    // the roots over the hashes 0xb10c0000 to 0xb10c0003
+    assert_eq!(
+        provider.block_hash_root(0..=2).unwrap(),
+        felt!("0x7f0642fcf2ae152922d0dc9667360c32e3bf3b2f40737f1f3964c338192793c")
+    );
+    assert_eq!(
+        provider.block_hash_root(0..=3).unwrap(),
+        felt!("0x7df46cfafa687c6281847435619c3bf6509d21c1cc9755a6f720f9519c9b524")
+    );
+    assert_eq!(
+        provider.block_hash_root(1..=2).unwrap(),
+        felt!("0x6ae6f5ee9e548fdec2c6524c22e40266dab664d24cfa195f07625fc5a7b88b2")
+    );
+    assert_eq!(provider.block_hash_root(1..=1).unwrap(), block_hash(1));
+
+    let err = provider.block_hash_root(1..=5).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::MissingBlock(4))
+    ));
+}
+
+#[test]
+fn contract_diffs_in_range() {
+    let address = ContractAddress::from(felt!("0x1337"));
+    let other = ContractAddress::from(felt!("0x1338"));
+
+    let provider = InMemoryProvider::new();
+    for num in 0..4 {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states.state_updates.nonce_updates.insert(other, FieldElement::from(num));
+        match num {
+            1 => {
+                states.state_updates.contract_updates.insert(address, felt!("0xc1"));
+                states.state_updates.nonce_updates.insert(address, felt!("0x1"));
+            }
+            3 => {
+                states
+                    .state_updates
+                    .storage_updates
+                    .insert(address, HashMap::from([(felt!("0x1"), felt!("0x10"))]));
+            }
+            _ => {}
+        }
+        insert_block(&provider, num, vec![], states);
+    }
+
+    let diffs = provider.contract_diffs_in_range(address, 0..=5).unwrap();
+
+    let mut first = StateUpdates::default();
+    first.contract_updates.insert(address, felt!("0xc1"));
+    first.nonce_updates.insert(address, felt!("0x1"));
+    let mut second = StateUpdates::default();
+    second.storage_updates.insert(address, HashMap::from([(felt!("0x1"), felt!("0x10"))]));
+
+    assert_eq!(diffs, vec![(1, first), (3, second)]);
+}
+
+#[test]
+fn noop_blocks_share_the_parent_snapshot() {
+    let address = ContractAddress::from(felt!("0x1337"));
+    let states = |value: FieldElement| {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states
+            .state_updates
+            .storage_updates
+            .insert(address, HashMap::from([(felt!("0x1"), value)]));
+        states
+    };
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![], states(felt!("0x1")));
+    insert_block(&provider, 1, vec![invoke_receipt(0)], Default::default());
+    insert_block(&provider, 2, vec![], states(felt!("0x2")));
+
+    {
+        let historical_states = provider.historical_states.read();
+        let snapshot = |num| historical_states.get(&num).unwrap();
+        assert!(Arc::ptr_eq(snapshot(0), snapshot(1)));
+        assert!(!Arc::ptr_eq(snapshot(1), snapshot(2)));
+    }
+
+    for (num, expected) in [(0, felt!("0x1")), (1, felt!("0x1")), (2, felt!("0x2"))] {
+        let state = provider.historical(BlockHashOrNumber::Num(num)).unwrap().unwrap();
+        assert_eq!(state.storage(address, felt!("0x1")).unwrap(), Some(expected));
+    }
+}
+
+#[test]
+fn declare_only_blocks_get_their_own_snapshot() {
+    let class_hash = felt!("0xc1a55");
+    let mut states = StateUpdatesWithDeclaredClasses::default();
+    states.declared_compiled_classes.insert(class_hash, (*ERC20_CONTRACT).clone());
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![], Default::default());
+    insert_block(&provider, 1, vec![], states);
+
+    let historical_states = provider.historical_states.read();
+    let snapshot = |num| historical_states.get(&num).unwrap();
+    assert!(!Arc::ptr_eq(snapshot(0), snapshot(1)));
+}
+
+#[test]
+fn noop_blocks_after_direct_writes_get_their_own_snapshot() {
+    let address = ContractAddress::from(felt!("0x1337"));
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![], Default::default());
+    provider.set_storage(address, felt!("0x1"), felt!("0x2")).unwrap();
+    insert_block(&provider, 1, vec![invoke_receipt(0)], Default::default());
+    insert_block(&provider, 2, vec![invoke_receipt(0)], Default::default());
+
+    {
+        let historical_states = provider.historical_states.read();
+        let snapshot = |num| historical_states.get(&num).unwrap();
+        assert!(!Arc::ptr_eq(snapshot(0), snapshot(1)));
+        assert!(Arc::ptr_eq(snapshot(1), snapshot(2)));
+    }
+
+    let state = provider.historical(BlockHashOrNumber::Num(0)).unwrap().unwrap();
+    assert_eq!(state.storage(address, felt!("0x1")).unwrap(), None);
+    for num in 1..=2 {
+        let state = provider.historical(BlockHashOrNumber::Num(num)).unwrap().unwrap();
+        assert_eq!(state.storage(address, felt!("0x1")).unwrap(), Some(felt!("0x2")));
+    }
+}
+
+#[test]
+fn memory_budget_counts_shared_snapshots_once() {
+    let address = ContractAddress::from(felt!("0x1337"));
+    let states = |num: u64| {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        let slots = (0..10).map(|i| (FieldElement::from(num * 10 + i), FieldElement::from(num)));
+        states.state_updates.storage_updates.insert(address, slots.collect());
+        states
+    };
+
+    // the budget only fits the latest state and a single snapshot of block 0.
+    let budget = {
+        let provider = InMemoryProvider::new();
+        insert_block(&provider, 0, vec![], states(0));
+        let snapshot_size =
+            provider.historical_states.read().get(&0).unwrap().inner.estimated_size();
+        provider.state.estimated_size() + snapshot_size
+    };
+
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    let provider = InMemoryProvider::new().with_memory_budget(budget);
+    let evicted_clone = Arc::clone(&evicted);
+    provider.set_on_evict_snapshot(Box::new(move |num: BlockNumber, _: &dyn StateProvider| {
+        evicted_clone.lock().push(num)
+    }));
+
+    insert_block(&provider, 0, vec![], states(0));
+    for num in 1..=5 {
+        insert_block(&provider, num, vec![invoke_receipt(0)], Default::default());
+    }
+
+    // the no-op blocks share the snapshot of block 0, so the budget is not exceeded
+    assert!(evicted.lock().is_empty());
+    for num in 0..=5 {
+        assert!(provider.historical(BlockHashOrNumber::Num(num)).unwrap().is_some());
+    }
+
+    // the shared snapshot is only released once all the blocks sharing it are evicted
+    insert_block(&provider, 6, vec![], states(6));
+    assert_eq!(*evicted.lock(), (0..=5).collect::<Vec<_>>());
+    assert!(provider.historical(BlockHashOrNumber::Num(6)).unwrap().is_some());
+}
+
+#[test]
+fn last_modifying_tx() {
+    let address = ContractAddress::from(felt!("0x1337"));
+    let states = |address: ContractAddress| {
+        let mut states = StateUpdatesWithDeclaredClasses::default();
+        states.state_updates.nonce_updates.insert(address, felt!("0x1"));
+        states
+    };
+
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![], states(address));
+    assert_eq!(provider.last_modifying_tx(address).unwrap(), None);
+
+    insert_block(&provider, 1, vec![invoke_receipt(0); 2], states(address));
+    assert_eq!(provider.last_modifying_tx(address).unwrap(), Some(tx_hash(1, 1)));
+
+    insert_block(&provider, 2, vec![invoke_receipt(0)], states(felt!("0x1338").into()));
+    insert_block(&provider, 3, vec![invoke_receipt(0); 3], states(address));
+    insert_block(&provider, 4, vec![invoke_receipt(0)], Default::default());
+    assert_eq!(provider.last_modifying_tx(address).unwrap(), Some(tx_hash(3, 2)));
+
+    assert_eq!(provider.last_modifying_tx(felt!("0xdead").into()).unwrap(), None);
+}
+
+#[test]
+fn export_and_import_classes() {
+    let legacy_hash = felt!("0x1");
+    let sierra_hash = felt!("0x2");
+    let other_hash = felt!("0x3");
+
+    let artifact = include_str!("../../../../db/benches/artifacts/dojo_world_240.json");
+    let compiled_class = parse_compiled_class(artifact).unwrap();
+    let sierra_class =
+        serde_json::from_str::<starknet::core::types::contract::SierraClass>(artifact)
+            .unwrap()
+            .flatten()
+            .unwrap();
+
+    let provider = InMemoryProvider::new();
+    provider.set_class(legacy_hash, (*ERC20_CONTRACT).clone()).unwrap();
+    provider.set_class(sierra_hash, compiled_class.clone()).unwrap();
+    provider.set_sierra_class(sierra_hash, sierra_class.clone()).unwrap();
+    provider.set_compiled_class_hash_of_class_hash(sierra_hash, felt!("0x22")).unwrap();
+    provider.set_class(other_hash, (*ERC20_CONTRACT).clone()).unwrap();
+
+    let bytes = provider.export_classes(Some(&[legacy_hash, sierra_hash])).unwrap();
+
+    let imported = InMemoryProvider::new();
+    imported.import_classes(&bytes).unwrap();
+
+    assert_eq!(imported.class_pair(legacy_hash).unwrap(), (Some((*ERC20_CONTRACT).clone()), None));
+    assert_eq!(
+        imported.class_pair(sierra_hash).unwrap(),
+        (Some(compiled_class), Some(sierra_class))
+    );
+    assert_eq!(imported.class_pair(other_hash).unwrap(), (None, None));
+    assert_eq!(
+        imported.compiled_class_hashes(&[legacy_hash, sierra_hash]).unwrap(),
+        vec![None, Some(felt!("0x22"))]
+    );
+    assert_eq!(imported.class_hash_by_compiled(felt!("0x22")).unwrap(), Some(sierra_hash));
+
+    // all the declared classes are exported by default
+    let all = InMemoryProvider::new();
+    all.import_classes(&provider.export_classes(None).unwrap()).unwrap();
+    assert!(all.class_pair(other_hash).unwrap().0.is_some());
+
+    let err = provider.export_classes(Some(&[felt!("0x4")])).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<InMemoryProviderError>(),
+        Some(InMemoryProviderError::ClassNotFound(hash)) if *hash == felt!("0x4")
+    ));
+}
+
+#[test]
+fn block_with_receipts() {
+    let provider = InMemoryProvider::new();
+    insert_block(&provider, 0, vec![invoke_receipt(1)], Default::default());
+    insert_block(&provider, 1, (2..5).map(invoke_receipt).collect(), Default::default());
+
+    let (block, receipts) =
+        provider.block_with_receipts(BlockHashOrNumber::Hash(block_hash(1))).unwrap().unwrap();
+    assert_eq!(block, provider.block(BlockHashOrNumber::Num(1)).unwrap().unwrap());
+    assert_eq!(block.body.len(), 3);
+    assert_eq!(receipts.len(), block.body.len());
+
+    for (tx, receipt) in block.body.iter().zip(&receipts) {
+        assert_eq!(Some(receipt), provider.receipt_by_hash(tx.hash).unwrap().as_ref());
+    }
+
+    assert_eq!(provider.block_with_receipts(BlockHashOrNumber::Num(2)).unwrap(), None);
+}
@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+
+use katana_primitives::contract::{
+    ClassHash, CompiledClassHash, CompiledContractClass, ContractAddress, Nonce, SierraClass,
+    StorageKey, StorageValue,
+};
+use parking_lot::Mutex;
+
+use crate::traits::contract::ContractClassProvider;
+use crate::traits::state::StateProvider;
+use crate::Result;
+
+/// The contracts and storage slots accessed through a [`TracingStateProvider`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AccessLog {
+    /// The storage slots read, grouped by contract.
+    pub storage: HashMap<ContractAddress, HashSet<StorageKey>>,
+    /// The contracts whose nonce was read.
+    pub nonces: HashSet<ContractAddress>,
+    /// The contracts whose class hash was read.
+    pub class_hashes: HashSet<ContractAddress>,
+}
+
+impl AccessLog {
+    /// Returns every contract that was accessed, regardless of the kind of access.
+    pub fn contracts(&self) -> HashSet<ContractAddress> {
+        self.storage.keys().chain(&self.nonces).chain(&self.class_hashes).copied().collect()
+    }
+}
+
+/// A [`StateProvider`] wrapper that records the storage slots and contracts accessed through it,
+/// eg. to build access lists.
+///
+/// Accesses are recorded whether or not the underlying read succeeds. Class lookups are not
+/// recorded.
+pub struct TracingStateProvider<S> {
+    inner: S,
+    log: Mutex<AccessLog>,
+}
+
+impl<S: StateProvider> TracingStateProvider<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, log: Mutex::new(AccessLog::default()) }
+    }
+
+    /// Returns the accesses recorded so far.
+    pub fn accessed_keys(&self) -> AccessLog {
+        self.log.lock().clone()
+    }
+
+    /// Returns the wrapped state provider.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: StateProvider> ContractClassProvider for TracingStateProvider<S> {
+    fn compiled_class_hash_of_class_hash(
+        &self,
+        hash: ClassHash,
+    ) -> Result<Option<CompiledClassHash>> {
+        self.inner.compiled_class_hash_of_class_hash(hash)
+    }
+
+    fn class(&self, hash: ClassHash) -> Result<Option<CompiledContractClass>> {
+        self.inner.class(hash)
+    }
+
+    fn sierra_class(&self, hash: ClassHash) -> Result<Option<SierraClass>> {
+        self.inner.sierra_class(hash)
+    }
+}
+
+impl<S: StateProvider> StateProvider for TracingStateProvider<S> {
+    fn nonce(&self, address: ContractAddress) -> Result<Option<Nonce>> {
+        self.log.lock().nonces.insert(address);
+        self.inner.nonce(address)
+    }
+
+    fn storage(
+        &self,
+        address: ContractAddress,
+        storage_key: StorageKey,
+    ) -> Result<Option<StorageValue>> {
+        self.log.lock().storage.entry(address).or_default().insert(storage_key);
+        self.inner.storage(address, storage_key)
+    }
+
+    fn class_hash_of_contract(&self, address: ContractAddress) -> Result<Option<ClassHash>> {
+        self.log.lock().class_hashes.insert(address);
+        self.inner.class_hash_of_contract(address)
+    }
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use starknet::macros::felt;
+
+    use super::TracingStateProvider;
+    use crate::providers::in_memory::InMemoryProvider;
+    use crate::traits::state::{StateFactoryProvider, StateProvider, StateWriter};
+
+    #[test]
+    fn records_state_reads() {
+        let provider = InMemoryProvider::new();
+        provider.set_storage(felt!("0x1").into(), felt!("0x10"), felt!("0x100")).unwrap();
+
+        let state = TracingStateProvider::new(provider.latest().unwrap());
+        assert_eq!(
+            state.storage(felt!("0x1").into(), felt!("0x10")).unwrap(),
+            Some(felt!("0x100"))
+        );
+        assert_eq!(state.storage(felt!("0x1").into(), felt!("0x11")).unwrap(), None);
+        assert_eq!(state.storage(felt!("0x2").into(), felt!("0x10")).unwrap(), None);
+        state.nonce(felt!("0x3").into()).unwrap();
+        state.class_hash_of_contract(felt!("0x1").into()).unwrap();
+        state.class_hash_of_contract(felt!("0x1").into()).unwrap();
+
+        let log = state.accessed_keys();
+        assert_eq!(
+            log.storage,
+            HashMap::from([
+                (felt!("0x1").into(), HashSet::from([felt!("0x10"), felt!("0x11")])),
+                (felt!("0x2").into(), HashSet::from([felt!("0x10")])),
+            ])
+        );
+        assert_eq!(log.nonces, HashSet::from([felt!("0x3").into()]));
+        assert_eq!(log.class_hashes, HashSet::from([felt!("0x1").into()]));
+        assert_eq!(
+            log.contracts(),
+            HashSet::from([felt!("0x1").into(), felt!("0x2").into(), felt!("0x3").into()])
+        );
+    }
+}